@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+
+use miette::{miette, IntoDiagnostic};
+
+use crate::{db::Db, types::MineResult};
+
+/// Async facade over `Db` for use from async services. Every operation runs on tokio's
+/// blocking thread pool, one at a time, since the underlying connection is synchronous.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Arc<Mutex<Db>>,
+}
+
+/// Total transparent value, total shielded value and spendable shielded value.
+pub struct ValuePools {
+    pub transparent: u64,
+    pub shielded: u64,
+    pub spendable_shielded: u64,
+}
+
+impl AsyncDb {
+    pub fn new(db: Db) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// Run `f` with exclusive access to the database on the blocking thread pool.
+    pub async fn run<T, F>(&self, f: F) -> miette::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Db) -> miette::Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut db = db
+                .lock()
+                .map_err(|_err| miette!("database lock is poisoned"))?;
+            f(&mut db)
+        })
+        .await
+        .into_diagnostic()?
+    }
+
+    /// Submit the pending transaction to the mempool, see `Db::submit_transaction`.
+    pub async fn submit(&self) -> miette::Result<Vec<u32>> {
+        self.run(|db| db.submit_transaction()).await
+    }
+
+    /// Submit the pending transaction with transparent change, see
+    /// `Db::submit_transaction_with_change`.
+    pub async fn submit_with_change(&self, fee: u64) -> miette::Result<(Option<u64>, Vec<u32>)> {
+        self.run(move |db| db.submit_transaction_with_change(fee))
+            .await
+    }
+
+    /// Mine the valid mempool transactions, see `Db::mine_at`.
+    pub async fn mine(&self) -> miette::Result<MineResult> {
+        self.run(|db| db.mine()).await
+    }
+
+    pub async fn mine_coinbase(&self, recipient: Option<String>) -> miette::Result<()> {
+        self.run(move |db| db.mine_coinbase(recipient)).await
+    }
+
+    /// Height, hash and note commitment tree root of the chain tip.
+    pub async fn tip(&self) -> miette::Result<(u32, [u8; 32], [u8; 32])> {
+        self.run(|db| db.tip_summary()).await
+    }
+
+    pub async fn value_pools(&self, account: Option<u32>) -> miette::Result<ValuePools> {
+        self.run(move |db| {
+            Ok(ValuePools {
+                transparent: db.get_total_transparent_value()?,
+                shielded: db.get_total_shielded_value(account)?,
+                spendable_shielded: db.get_spendable_shielded_value(account)?,
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests;
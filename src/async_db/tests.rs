@@ -0,0 +1,25 @@
+use super::*;
+
+#[tokio::test]
+async fn submit_and_mine_through_the_facade() {
+    let db = AsyncDb::new(Db::new_in_memory().unwrap());
+    db.run(|db| {
+        db.conjure_utxo(1000)?;
+        db.spend_utxo(1)?;
+        db.create_utxo(900)
+    })
+    .await
+    .unwrap();
+    assert_eq!(db.submit().await.unwrap().len(), 1);
+    db.mine().await.unwrap();
+    db.mine_coinbase(None).await.unwrap();
+
+    let (height, _hash, _root) = db.tip().await.unwrap();
+    assert_eq!(height, 2);
+    let pools = db.value_pools(None).await.unwrap();
+    assert_eq!(
+        pools.transparent,
+        900 + db.run(|db| Ok(db.params.block_reward(2))).await.unwrap()
+    );
+    assert_eq!((pools.shielded, pools.spendable_shielded), (0, 0));
+}
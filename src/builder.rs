@@ -0,0 +1,113 @@
+use miette::{miette, IntoDiagnostic};
+use orchard::{
+    builder::BundleType, bundle::Flags, keys::SpendAuthorizingKey, value::NoteValue, Address,
+};
+use rand::SeedableRng;
+
+use crate::{
+    db::{CircuitKeys, Db},
+    params::NetworkParams,
+    types::{Action, Output, Transaction},
+};
+
+/// A transaction built in memory, without going through the staging tables. Transparent inputs
+/// aren't checked until the transaction is mined.
+pub struct TransactionBuilder {
+    bundle: orchard::builder::Builder,
+    inputs: Vec<u32>,
+    outputs: Vec<Output>,
+    signing_keys: Vec<SpendAuthorizingKey>,
+}
+
+impl TransactionBuilder {
+    /// The Orchard bundle is anchored at the current bundle anchor, see `Db::get_bundle_anchor`.
+    pub fn new(tx: &rusqlite::Transaction, params: &NetworkParams) -> miette::Result<Self> {
+        let anchor = Db::get_bundle_anchor(tx, params)?;
+        let bundle = orchard::builder::Builder::new(
+            BundleType::Transactional {
+                flags: Flags::ENABLED,
+                bundle_required: false,
+            },
+            anchor,
+        );
+        Ok(Self {
+            bundle,
+            inputs: vec![],
+            outputs: vec![],
+            signing_keys: vec![],
+        })
+    }
+
+    pub fn add_transparent_input(&mut self, utxo_id: u32) {
+        self.inputs.push(utxo_id);
+    }
+
+    pub fn add_transparent_output(&mut self, value: u64) {
+        self.outputs.push(Output { value });
+    }
+
+    /// Spend wallet note `note_id`, signing with the key of the account it belongs to.
+    pub fn add_note_spend(
+        &mut self,
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        note_id: u32,
+    ) -> miette::Result<()> {
+        let note = Db::get_note(tx, note_id)?;
+        let sk = Db::get_sk(tx, Db::get_note_account(tx, note_id)?)?;
+        let fvk = orchard::keys::FullViewingKey::from(&sk);
+        let merkle_path = Db::get_spend_path(tx, params, note_id, &note)?;
+        self.bundle
+            .add_spend(fvk, note, merkle_path)
+            .map_err(|err| miette!("failed to spend note {note_id}: {err}"))?;
+        self.signing_keys.push(SpendAuthorizingKey::from(&sk));
+        Ok(())
+    }
+
+    pub fn add_shielded_output(
+        &mut self,
+        recipient: Address,
+        value: u64,
+        memo: Option<[u8; 512]>,
+    ) -> miette::Result<()> {
+        self.bundle
+            .add_output(None, recipient, NoteValue::from_raw(value), memo)
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// The Orchard bundle is always proven and signed: Orchard only finalizes the signatures of
+    /// proven bundles, and transactions with actions are invalid without them.
+    pub fn build(self, keys: &CircuitKeys) -> miette::Result<Transaction> {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let bundle = self.bundle.build::<i64>(&mut rng).into_diagnostic()?;
+        let Some((bundle, _metadata)) = bundle else {
+            return Ok(Transaction {
+                inputs: self.inputs,
+                outputs: self.outputs,
+                actions: vec![],
+                value_balance_orchard: 0,
+                authorization: None,
+            });
+        };
+        // The txid doesn't cover the authorization, so it can be signed before there is one.
+        let sighash = Transaction {
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            actions: bundle.actions().iter().map(Action::from).collect(),
+            value_balance_orchard: *bundle.value_balance(),
+            authorization: None,
+        }
+        .txid();
+        let bundle = bundle
+            .create_proof(keys.proving_key(), &mut rng)
+            .map_err(|err| miette!("failed to prove Orchard bundle: {err}"))?
+            .apply_signatures(&mut rng, sighash, &self.signing_keys)
+            .map_err(|err| miette!("failed to sign Orchard bundle: {err}"))?;
+        Ok(Transaction::from_authorized_bundle(
+            self.inputs,
+            self.outputs,
+            &bundle,
+        ))
+    }
+}
@@ -1,8 +1,31 @@
 use clap::{Parser, Subcommand};
 
+use crate::{types::TransactionKind, units::parse_amount};
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    /// Database file, defaults to orchard.db3 in the platform data directory
+    #[arg(long, visible_alias = "db-path", global = true)]
+    pub db: Option<std::path::PathBuf>,
+    /// Directory holding orchard.db3, instead of the platform data directory
+    #[arg(long, global = true, conflicts_with = "db")]
+    pub datadir: Option<std::path::PathBuf>,
+    /// Use a throwaway in-memory database, nothing is written to disk
+    #[arg(long, global = true, conflicts_with_all = ["db", "datadir"])]
+    pub in_memory: bool,
+    /// Require valid Orchard proofs in mined blocks, not just valid signatures. Without it,
+    /// invalid proofs are accepted
+    #[arg(long, global = true)]
+    pub verify_proofs: bool,
+    /// Reject transactions paying a lower fee, in coins. The default fee of submit-txn is
+    /// raised to at least this
+    #[arg(long, global = true, value_parser = parse_amount)]
+    pub min_fee: Option<u64>,
+    /// Checkpoint the database write-ahead log once it grows past this many pages, 0 disables
+    /// automatic checkpoints. Defaults to SQLite's 1000 pages
+    #[arg(long, global = true)]
+    pub wal_autocheckpoint: Option<u32>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -12,30 +35,224 @@ pub enum Commands {
     /// Print out wallet data
     Wallet,
     /// Create a new transparent utxo in pending transaction
-    CreateUtxo { value: u64 },
+    CreateUtxo {
+        /// Value in coins, like 1.5
+        #[arg(value_parser = parse_amount)]
+        value: u64,
+    },
     /// Create spend an existing transparent utxo in pending transaction
     SpendUtxo { utxo_id: u32 },
+    /// Spend transparent utxos covering a value in pending transaction
+    SpendValue {
+        /// Value in coins, like 1.5
+        #[arg(value_parser = parse_amount)]
+        value: u64,
+    },
     /// Create a new note in pending transaction
     CreateNote {
+        /// Value in coins, like 1.5
+        #[arg(value_parser = parse_amount)]
         value: u64,
         recipient: Option<String>,
+        /// Pay the note to a fresh change address of this wallet
+        #[arg(long, conflicts_with = "recipient")]
+        change: bool,
+        /// UTF-8 text memo of at most 512 bytes
+        #[arg(long)]
+        memo: Option<String>,
+        /// Account whose address receives the note when no recipient is given
+        #[arg(long, default_value_t = 0)]
+        account: u32,
     },
     /// Spend a note in pending transaction
     SpendNote { note_id: u32 },
-    /// Submit pending transaction to mempool
-    SubmitTxn,
+    /// Check that a note can be spent by building and validating a transaction spending it,
+    /// without submitting it
+    DrySpend {
+        note_id: u32,
+        /// Value in coins paid back to the wallet, the rest minus the fee is change
+        #[arg(value_parser = parse_amount)]
+        amount: u64,
+        /// Fee in coins, defaults to the minimum fee
+        #[arg(long, value_parser = parse_amount)]
+        fee: Option<u64>,
+    },
+    /// Estimate the fee of the pending transaction from its number of inputs, outputs and
+    /// Orchard actions
+    EstimateFee,
+    /// Show the change left over if the pending transaction pays the given fee
+    PreviewChange {
+        /// Fee in coins, like 0.000001
+        #[arg(value_parser = parse_amount)]
+        fee: u64,
+    },
+    /// Submit pending transaction to mempool, returning the change of transparent inputs to a
+    /// new utxo
+    SubmitTxn {
+        /// Fee in coins paid out of the transparent inputs, defaults to the minimum fee
+        #[arg(long, value_parser = parse_amount)]
+        fee: Option<u64>,
+        /// Don't create a change utxo, whatever is left over is paid as fee
+        #[arg(long, conflicts_with = "fee")]
+        no_change: bool,
+    },
+    /// Print a one line summary of every transaction waiting in the mempool
+    Mempool {
+        /// Only transactions of this kind: transparent, shield, deshield or shielded
+        #[arg(long)]
+        kind: Option<TransactionKind>,
+    },
+    /// Print the transactions waiting in the mempool
+    InspectMempool {
+        /// Only transactions of this kind: transparent, shield, deshield or shielded
+        #[arg(long)]
+        kind: Option<TransactionKind>,
+    },
+    /// Print the mempool transactions each mempool transaction spends pending utxos of
+    MempoolDeps,
+    /// Attach a label to a transaction
+    LabelTransaction { txid: String, label: String },
     /// Clear pending transaction in mempool
     ClearTxn,
+    /// Show the staged transaction left over from a previous run
+    ResumePending,
+    /// Time mining a workload of shielding transactions, use on a scratch database
+    Bench { blocks: u32, txns_per_block: u32 },
+    /// Mine a block containing only the mempool transactions with the given txids
+    MineSelected { txids: Vec<String> },
+    /// Mine a block issuing the block reward to a transparent utxo or a shielded address,
+    /// defaulting to the miner address if one is set
+    MineCoinbase { recipient: Option<String> },
+    /// Set the shielded address coinbase rewards are paid to, or clear it without an address
+    SetMinerAddress { address: Option<String> },
+    /// Print the shielded address coinbase rewards are paid to
+    GetMinerAddress,
+    /// Summarize the state of the node and wallet
+    Status,
+    /// Check that all issued value is accounted for
+    SupplyAudit,
+    /// Find received payments whose memo contains the query
+    SearchMemos { query: String },
+    /// Print height, hash and note commitment tree root of the chain tip
+    Tip,
+    /// Print height, total fees and total number of transactions of the chain
+    Chain,
+    /// Print the number of note commitments in the chain, whoever the notes belong to
+    TreeSize,
+    /// Print the fee and transactions of a block
+    Block { id: u32 },
+    /// Print the ommer hashes of the note commitment tree frontier at the tip
+    FrontierOmmers,
+    /// Write all blocks to a file
+    ExportChain { path: std::path::PathBuf },
+    /// Connect the blocks of an exported chain file on top of an empty chain
+    ImportChain { path: std::path::PathBuf },
+    /// Print a block as hex, for replaying it into another database
+    ExportBlock { height: u32 },
+    /// Connect a block printed by export-block on top of the tip
+    ImportBlock { hex: String },
+    /// Check every block from genesis
+    VerifyChain,
+    /// Check that the stored addresses derive from the wallet seed and that the supply audit
+    /// balances, warning about fees that left the system
+    CheckDb,
+    /// Compare this chain with the chain in another database
+    CompareChains { other_path: std::path::PathBuf },
     /// Mine a block
-    Mine,
+    Mine {
+        /// Block timestamp in seconds since the unix epoch, defaults to now
+        #[arg(long)]
+        timestamp: Option<i64>,
+        /// Delete invalid transactions from the mempool instead of leaving them pending
+        #[arg(long)]
+        drop_rejected: bool,
+    },
+    /// Undo the tip block, dropping its transactions and the mempool transactions spending their
+    /// outputs
+    DisconnectTip,
+    /// Show the block that mining would produce now, without mining it
+    PeekBlock {
+        /// Block timestamp in seconds since the unix epoch, defaults to now
+        #[arg(long)]
+        timestamp: Option<i64>,
+    },
     /// Get wallet seed mnemonic 12 words
     GetMnemonic,
+    /// Replace the wallet seed with a backed up mnemonic phrase
+    RestoreWallet {
+        phrase: String,
+        /// Restore even if the wallet has notes, dropping them
+        #[arg(long)]
+        force: bool,
+    },
+    /// Create a new wallet with a fresh seed in a new database file, printing its mnemonic
+    InitWallet { path: std::path::PathBuf },
+    /// Add a new account derived from the wallet seed
+    NewAccount,
     /// Get new shielded address
-    GetNewAddress,
+    GetNewAddress {
+        #[arg(long, default_value_t = 0)]
+        account: u32,
+    },
+    /// Print the address previously generated at a diversifier index
+    GetAddressAt {
+        index: u32,
+        #[arg(long, default_value_t = 0)]
+        account: u32,
+    },
+    /// Print the incoming viewing key, for sharing read-only access to received payments
+    GetIvk,
+    /// Decrypt the outputs of a block with an incoming viewing key
+    ScanBlock { block_id: u32, ivk: String },
+    /// Decrypt the outputs of a block paying this wallet
+    MyOutputs { block_id: u32 },
+    /// Print the wallet fingerprint
+    Fingerprint,
+    /// Get new internal shielded address for change
+    GetChangeAddress,
     /// Get total transparent and shielded value
-    ValuePools,
+    ValuePools {
+        /// Only count the shielded value of this account
+        #[arg(long)]
+        account: Option<u32>,
+    },
+    /// Get total transparent and shielded value as of a past block
+    BalanceAt { block_id: u32 },
     /// Create a new UTXO out of thin air
-    ConjureUtxo { value: u64 },
+    ConjureUtxo {
+        /// Value in coins, like 1.5
+        #[arg(value_parser = parse_amount)]
+        value: u64,
+    },
+    /// Reserve a transparent utxo so that it can't be spent
+    LockUtxo { utxo_id: u32 },
+    /// Release a reserved transparent utxo
+    UnlockUtxo { utxo_id: u32 },
     /// Get all transparent UTXOs
     GetUtxos,
+    /// List wallet notes that have been spent and the blocks that spent them
+    ListSpentNotes,
+    /// Delete wallet notes spent more than depth blocks below the tip
+    PruneNotes { depth: u32 },
+    /// Show where a transparent utxo was created and spent
+    UtxoInfo { id: u32 },
+    /// Print every nullifier in the nullifier set and the block it was revealed in
+    DumpNullifiers,
+    /// Print the nullifier a wallet note reveals when spent and whether it has been
+    NoteNullifier { note_id: u32 },
+    /// Compare the merkle path a spend of a wallet note would use with the current anchor
+    DiagnoseSpend { note_id: u32 },
+    /// List notes in the chain paying this wallet that aren't tracked
+    FindMissing,
+    /// Recompute a note's witness from the chain
+    RebuildWitness { note_id: u32 },
+    /// Print a wallet note with its witness as JSON
+    ExportNote { note_id: u32 },
+    /// Import a note exported by a wallet sharing this seed, reading JSON from stdin
+    ImportNote,
+    /// Encrypt the wallet seed with a password
+    EncryptWallet,
 }
+
+#[cfg(test)]
+mod tests;
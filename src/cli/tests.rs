@@ -0,0 +1,32 @@
+use super::*;
+use crate::units::COIN;
+
+fn parse(args: &[&str]) -> Result<Commands, clap::Error> {
+    Cli::try_parse_from(["orchard_sandbox"].iter().chain(args)).map(|cli| cli.command)
+}
+
+#[test]
+fn value_arguments_take_decimal_coin_amounts() {
+    assert!(matches!(
+        parse(&["create-utxo", "1.00000000"]),
+        Ok(Commands::CreateUtxo { value: COIN })
+    ));
+    assert!(matches!(
+        parse(&["create-note", "0.5"]),
+        Ok(Commands::CreateNote {
+            value: 50_000_000,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse(&["submit-txn", "--fee", "0.00000001"]),
+        Ok(Commands::SubmitTxn { fee: Some(1), .. })
+    ));
+    for value in ["0.000000001", "1.5coins", "1,5"] {
+        assert!(
+            parse(&["create-utxo", value]).is_err(),
+            "{value:?} was accepted"
+        );
+    }
+    assert!(parse(&["create-utxo", "--", "-1"]).is_err());
+}
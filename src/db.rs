@@ -1,5 +1,16 @@
-use crate::types::{Block, Output};
+use crate::{
+    builder::TransactionBuilder,
+    params::NetworkParams,
+    types::{
+        BenchReport, Block, BlockPreview, ChainComparison, ChainSummary, MempoolDependency,
+        MineResult, Output, PendingTransactionState, RejectedTransaction, ShieldedOutput,
+        SpendDiagnosis, SpendableNote, Status, SupplyAudit, TransactionSelection, UtxoInfo,
+        WalletNote,
+    },
+    units::Amount,
+};
 use bip39::{Mnemonic, Seed};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
 use incrementalmerkletree::{
     frontier::{CommitmentTree, Frontier, NonEmptyFrontier},
     witness::IncrementalWitness,
@@ -8,23 +19,207 @@ use incrementalmerkletree::{
 use miette::{miette, IntoDiagnostic};
 use orchard::{
     builder::BundleType,
-    bundle::Flags,
+    bundle::Authorized,
+    circuit::{ProvingKey, VerifyingKey},
     note::{ExtractedNoteCommitment, Nullifier, RandomSeed, Rho},
     tree::MerkleHashOrchard,
     value::NoteValue,
     Address, Anchor, Note,
 };
-use rand::SeedableRng;
-use rusqlite::Connection;
-use rusqlite_migration::{Migrations, M};
+use rusqlite::{Connection, OpenFlags};
+use rusqlite_migration::{HookError, HookResult, Migrations, M};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
 use zip32::AccountId;
 
+pub const DB_FILE_NAME: &str = "orchard.db3";
+
+/// `config` key of the shielded address coinbase rewards are paid to by default.
+const MINER_ADDRESS_KEY: &str = "miner_address";
+
+/// Depth of the note commitment tree. Orchard anchors and merkle paths are defined for this
+/// depth only, so unlike the other consensus parameters it is not part of `NetworkParams`.
+pub const NOTE_COMMITMENT_TREE_DEPTH: u8 = orchard::NOTE_COMMITMENT_TREE_DEPTH as u8;
+
+/// Exported chain files start with this magic, followed by the format version, the block count
+/// and a blake3 checksum of the payload.
+const EXPORT_MAGIC: &[u8; 8] = b"ORCHCHN\0";
+const EXPORT_VERSION: u32 = 1;
+const EXPORT_HEADER_LEN: usize = 8 + 4 + 4 + 32;
+
+/// PBKDF2-HMAC-SHA256 rounds used to derive the wallet seed encryption key from a password.
+const SEED_KDF_ROUNDS: u32 = 600_000;
+
+/// How long to retry when another process holds a lock on the database.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct Db {
     pub conn: Connection,
+    pub params: NetworkParams,
+    pub keys: CircuitKeys,
+}
+
+/// Halo2 keys of the Orchard circuit. Building them is slow, so each is only built the first
+/// time it's needed and then kept for the lifetime of the `Db`.
+#[derive(Default)]
+pub struct CircuitKeys {
+    proving_key: OnceLock<ProvingKey>,
+    verifying_key: OnceLock<VerifyingKey>,
+}
+
+impl CircuitKeys {
+    pub fn proving_key(&self) -> &ProvingKey {
+        self.proving_key.get_or_init(ProvingKey::build)
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        self.verifying_key.get_or_init(VerifyingKey::build)
+    }
 }
 
 impl Db {
     pub fn new() -> miette::Result<Self> {
+        Self::open(Self::connect(Self::default_path()?)?, None)
+    }
+
+    pub fn new_at(path: impl AsRef<Path>) -> miette::Result<Self> {
+        Self::open(Self::connect(path)?, None)
+    }
+
+    /// Database that lives only as long as this `Db`, for ephemeral runs.
+    pub fn new_in_memory() -> miette::Result<Self> {
+        let mut conn = Connection::open_in_memory().into_diagnostic()?;
+        Self::migrate(&mut conn)?;
+        Self::open(conn, None)
+    }
+
+    /// Database in the platform data directory, so that every working directory uses the same
+    /// wallet: `$XDG_DATA_HOME` or `~/.local/share` on Linux, `~/Library/Application Support`
+    /// on macOS and `%APPDATA%` on Windows. The directory is created if it doesn't exist.
+    pub fn default_path() -> miette::Result<PathBuf> {
+        let env_dir = |name: &str| {
+            std::env::var_os(name)
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+        };
+        let home = || env_dir("HOME").ok_or_else(|| miette!("home directory is not set"));
+        let data_dir = if cfg!(windows) {
+            env_dir("APPDATA").ok_or_else(|| miette!("APPDATA is not set"))?
+        } else if cfg!(target_os = "macos") {
+            home()?.join("Library").join("Application Support")
+        } else {
+            match env_dir("XDG_DATA_HOME") {
+                Some(data_dir) => data_dir,
+                None => home()?.join(".local").join("share"),
+            }
+        };
+        let data_dir = data_dir.join("orchard_sandbox");
+        std::fs::create_dir_all(&data_dir).into_diagnostic()?;
+        Ok(data_dir.join(DB_FILE_NAME))
+    }
+
+    /// Create a new wallet with a fresh seed at `path`, refusing to touch an existing file.
+    pub fn init_fresh(path: impl AsRef<Path>) -> miette::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(miette!(
+                "can't create wallet {}: file already exists",
+                path.display()
+            ));
+        }
+        Self::new_at(path)
+    }
+
+    /// Open a wallet whose seed is encrypted at rest with `password`. A wallet with a plaintext
+    /// seed gets its seed encrypted.
+    pub fn new_encrypted(path: impl AsRef<Path>, password: &str) -> miette::Result<Self> {
+        Self::open(Self::connect(path)?, Some(password))
+    }
+
+    pub fn is_encrypted(path: impl AsRef<Path>) -> miette::Result<bool> {
+        let mut conn = Self::connect(path)?;
+        let tx = conn.transaction().into_diagnostic()?;
+        Self::is_seed_encrypted(&tx)
+    }
+
+    /// Checkpoint the write-ahead log into the database file whenever it grows past `pages`
+    /// pages, 0 disables automatic checkpoints. SQLite's default is 1000 pages.
+    pub fn set_wal_autocheckpoint(&self, pages: u32) -> miette::Result<()> {
+        let applied: u32 = self
+            .conn
+            .pragma_update_and_check(None, "wal_autocheckpoint", pages, |row| row.get(0))
+            .into_diagnostic()?;
+        if applied != pages {
+            return Err(miette!(
+                "failed to set wal_autocheckpoint to {pages}, it is {applied}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn open(conn: Connection, password: Option<&str>) -> miette::Result<Self> {
+        // Keep the decrypted seed of an encrypted wallet off the disk.
+        conn.pragma_update(None, "temp_store", "MEMORY")
+            .into_diagnostic()?;
+
+        let mut db = Db {
+            conn,
+            params: NetworkParams::default(),
+            keys: CircuitKeys::default(),
+        };
+
+        let tx = db.conn.transaction().into_diagnostic()?;
+        if Db::is_seed_encrypted(&tx)? {
+            let password = password
+                .ok_or_else(|| miette!("wallet seed is encrypted, a password is required"))?;
+            Db::unlock_seed(&tx, password)?;
+        } else {
+            if Db::get_mnemonic(&tx).is_err() {
+                Db::generate_seed(&tx)?;
+            }
+            if let Some(password) = password {
+                Db::encrypt_seed(&tx, password)?;
+            }
+        }
+        tx.commit().into_diagnostic()?;
+
+        Ok(db)
+    }
+
+    fn connect(path: impl AsRef<Path>) -> miette::Result<Connection> {
+        let path = path.as_ref();
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            if !parent.is_dir() {
+                return Err(miette!(
+                    "can't open database {}: directory {} does not exist",
+                    path.display(),
+                    parent.display()
+                ));
+            }
+        }
+        let mut conn = Connection::open(path).into_diagnostic()?;
+        // Wait out short-lived locks held by another instance before giving up.
+        conn.busy_timeout(LOCK_TIMEOUT).into_diagnostic()?;
+
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .map_err(lock_error)?;
+
+        Self::migrate(&mut conn)?;
+
+        Ok(conn)
+    }
+
+    fn migrate(conn: &mut Connection) -> miette::Result<()> {
         // 1️⃣ Define migrations
         let migrations = Migrations::new(vec![
             M::up(
@@ -102,31 +297,80 @@ impl Db {
                     value INTEGER NOT NULL
             );",
             ),
+            M::up(
+                "ALTER TABLE utxos ADD COLUMN created_block INTEGER REFERENCES blocks(id);
+                ALTER TABLE utxos ADD COLUMN spent_block INTEGER REFERENCES blocks(id);",
+            ),
+            M::up(
+                "CREATE TABLE encrypted_wallet_seed(
+                    ciphertext BLOB NOT NULL,
+                    salt BLOB NOT NULL,
+                    nonce BLOB NOT NULL
+            );",
+            ),
+            M::up(
+                "CREATE TABLE change_addresses(
+                    id INTEGER PRIMARY KEY,
+                    address BLOB NOT NULL
+            );",
+            ),
+            M::up("ALTER TABLE utxos ADD COLUMN mempool_tx INTEGER REFERENCES transactions(id);"),
+            M::up("ALTER TABLE blocks ADD COLUMN timestamp INTEGER;"),
+            M::up("ALTER TABLE blocks ADD COLUMN issuance INTEGER NOT NULL DEFAULT 0;"),
+            M::up("ALTER TABLE blocks ADD COLUMN hash BLOB;"),
+            M::up(
+                "ALTER TABLE notes ADD COLUMN nullifier BLOB;
+                ALTER TABLE notes ADD COLUMN spent_block INTEGER;",
+            ),
+            M::up("ALTER TABLE notes ADD COLUMN created_block INTEGER;"),
+            M::up(
+                "CREATE TABLE transaction_labels(
+                    txid BLOB PRIMARY KEY,
+                    label TEXT NOT NULL
+            );",
+            ),
+            M::up("ALTER TABLE utxos ADD COLUMN locked INTEGER NOT NULL DEFAULT 0;"),
+            M::up(
+                "ALTER TABLE shielded_outputs ADD COLUMN memo BLOB;
+                ALTER TABLE notes ADD COLUMN memo BLOB;",
+            ),
+            M::up("ALTER TABLE nullifiers ADD COLUMN block_id INTEGER REFERENCES blocks(id);"),
+            M::up(
+                "CREATE TABLE config(
+                    key TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+            );",
+            ),
+            M::up(
+                "CREATE TABLE accounts(
+                    id INTEGER PRIMARY KEY
+            );
+                INSERT INTO accounts (id) VALUES (0);
+                ALTER TABLE notes ADD COLUMN account INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE addresses ADD COLUMN account INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE addresses ADD COLUMN diversifier_index INTEGER;
+                UPDATE addresses SET diversifier_index = id;
+                ALTER TABLE change_addresses ADD COLUMN account INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE change_addresses ADD COLUMN diversifier_index INTEGER;
+                UPDATE change_addresses SET diversifier_index = id;",
+            ),
+            M::up_with_hook("", add_transaction_authorization),
+            M::up("ALTER TABLE utxos ADD COLUMN conjured_height INTEGER;"),
         ]);
 
-        let mut conn = Connection::open("./orchard.db3").into_diagnostic()?;
-
-        conn.pragma_update_and_check(None, "journal_mode", &"WAL", |_| Ok(()))
-            .into_diagnostic()?;
-
         // 2️⃣ Update the database schema, atomically
-        migrations.to_latest(&mut conn).into_diagnostic()?;
-
-        let mut db = Db { conn };
-
-        let tx = db.conn.transaction().into_diagnostic()?;
-        if !Db::get_mnemonic(&tx).is_ok() {
-            Db::generate_seed(&tx)?;
-        }
-        tx.commit().into_diagnostic()?;
+        migrations.to_latest(conn).map_err(|err| match err {
+            rusqlite_migration::Error::RusqliteError { err, .. } => lock_error(err),
+            err => miette!(err),
+        })?;
 
-        Ok(db)
+        Ok(())
     }
 
     pub fn get_inputs(tx: &rusqlite::Transaction) -> miette::Result<Vec<u32>> {
         let mut statement = tx.prepare("SELECT utxo_id FROM inputs").into_diagnostic()?;
         let inputs: Vec<u32> = statement
-            .query_map([], |row| Ok(row.get(0)?))
+            .query_map([], |row| row.get(0))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
@@ -136,7 +380,7 @@ impl Db {
     pub fn get_outputs(tx: &rusqlite::Transaction) -> miette::Result<Vec<Output>> {
         let mut statement = tx.prepare("SELECT value FROM outputs").into_diagnostic()?;
         let outputs: Vec<u64> = statement
-            .query_map([], |row| Ok(row.get(0)?))
+            .query_map([], |row| row.get(0))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
@@ -149,23 +393,113 @@ impl Db {
             .prepare("SELECT note_id FROM shielded_inputs")
             .into_diagnostic()?;
         let outputs: Vec<u32> = statement
-            .query_map([], |row| Ok(row.get(0)?))
+            .query_map([], |row| row.get(0))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
         Ok(outputs)
     }
 
-    pub fn get_shielded_outputs(tx: &rusqlite::Transaction) -> miette::Result<Vec<(Vec<u8>, u64)>> {
+    pub fn get_shielded_outputs(tx: &rusqlite::Transaction) -> miette::Result<Vec<ShieldedOutput>> {
         let mut statement = tx
-            .prepare("SELECT recipient, value FROM shielded_outputs")
+            .prepare("SELECT recipient, value, memo FROM shielded_outputs")
             .into_diagnostic()?;
-        let outputs: Vec<_> = statement
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        let outputs: Vec<(Vec<u8>, u64, Option<Vec<u8>>)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
-        Ok(outputs)
+        outputs
+            .into_iter()
+            .map(|(recipient, value, memo)| {
+                let memo = memo
+                    .map(|memo| {
+                        memo.try_into()
+                            .map_err(|_err| miette!("wrong staged memo length"))
+                    })
+                    .transpose()?;
+                Ok(ShieldedOutput {
+                    recipient,
+                    value,
+                    memo,
+                })
+            })
+            .collect()
+    }
+
+    /// Staged components are stored in the database, so a transaction that is being built
+    /// survives restarts until it is submitted or cleared.
+    pub fn pending_transaction_state(&mut self) -> miette::Result<PendingTransactionState> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let state = PendingTransactionState {
+            inputs: Self::get_inputs(&tx)?,
+            outputs: Self::get_outputs(&tx)?,
+            shielded_inputs: Self::get_shielded_inputs(&tx)?,
+            shielded_outputs: Self::get_shielded_outputs(&tx)?,
+        };
+        Ok(state)
+    }
+
+    /// Value left over for change if the staged transaction pays `fee`. Negative means the
+    /// staged inputs don't cover the outputs and the fee.
+    pub fn preview_change(&mut self, fee: u64) -> miette::Result<i64> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::get_staged_change(&tx, fee)
+    }
+
+    /// Stage a transparent output returning the change of the staged transparent inputs after
+    /// paying `fee`, returning its value. Nothing is staged if there are no transparent inputs
+    /// or no change.
+    fn stage_transparent_change(
+        tx: &rusqlite::Transaction,
+        fee: u64,
+    ) -> miette::Result<Option<u64>> {
+        if Self::get_inputs(tx)?.is_empty() {
+            return Ok(None);
+        }
+        let change = Self::get_staged_change(tx, fee)?;
+        if change < 0 {
+            return Err(miette!(
+                "insufficient funds, the staged inputs are {} short of the outputs and fee {fee}",
+                -change
+            ));
+        }
+        if change == 0 {
+            return Ok(None);
+        }
+        tx.execute("INSERT INTO outputs (value) VALUES (?1)", [change])
+            .into_diagnostic()?;
+        Ok(Some(u64::try_from(change).into_diagnostic()?))
+    }
+
+    fn get_staged_change(tx: &rusqlite::Transaction, fee: u64) -> miette::Result<i64> {
+        let mut values_in = Vec::new();
+        for utxo_id in Self::get_inputs(tx)? {
+            values_in.push(Self::get_utxo_value(tx, utxo_id)?);
+        }
+        for note_id in Self::get_shielded_inputs(tx)? {
+            let value: i64 = tx
+                .query_row("SELECT value FROM notes WHERE id = ?1", [note_id], |row| {
+                    row.get(0)
+                })
+                .into_diagnostic()?;
+            values_in.push(stored_value(value)?);
+        }
+        let value_in = sum_values(values_in)?;
+        let value_out = sum_values(
+            Self::get_outputs(tx)?
+                .iter()
+                .map(|output| output.value)
+                .chain(
+                    Self::get_shielded_outputs(tx)?
+                        .iter()
+                        .map(|output| output.value),
+                ),
+        )?;
+        value_in
+            .checked_sub(value_out)
+            .and_then(|change| change.checked_sub(signed_value(fee).ok()?))
+            .ok_or_else(|| miette!("staged transaction values are out of range"))
     }
 
     pub fn create_utxo(&self, value: u64) -> miette::Result<()> {
@@ -175,33 +509,185 @@ impl Db {
         Ok(())
     }
 
-    pub fn spend_utxo(&self, utxo_id: u32) -> miette::Result<()> {
-        self.conn
-            .execute("INSERT INTO inputs (utxo_id) VALUES (?1)", [utxo_id])
+    pub fn spend_utxo(&mut self, utxo_id: u32) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let locked: bool =
+            match tx.query_row("SELECT locked FROM utxos WHERE id = ?1", [utxo_id], |row| {
+                row.get(0)
+            }) {
+                Ok(locked) => locked,
+                Err(rusqlite::Error::QueryReturnedNoRows) => false,
+                Err(err) => return Err(err).into_diagnostic(),
+            };
+        if locked {
+            return Err(miette!("utxo {utxo_id} is locked, unlock it to spend it"));
+        }
+        Self::check_utxo_maturity(&tx, &self.params, utxo_id)?;
+        tx.execute("INSERT INTO inputs (utxo_id) VALUES (?1)", [utxo_id])
+            .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Pick spendable utxos covering `target`: a single utxo of exactly `target` if there is one,
+    /// otherwise the largest utxos first. Locked, immature and already staged utxos are skipped,
+    /// and ties are broken by id so the selection is deterministic.
+    pub fn select_utxos(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        target: u64,
+    ) -> miette::Result<Vec<(u32, u64)>> {
+        let mut statement = tx
+            .prepare(
+                "SELECT id, value FROM utxos
+                WHERE spent_block IS NULL AND mempool_tx IS NULL AND locked = 0
+                AND id NOT IN (SELECT utxo_id FROM inputs)
+                ORDER BY value DESC, id",
+            )
+            .into_diagnostic()?;
+        let candidates: Vec<(u32, u64)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        let candidates: Vec<(u32, u64)> = candidates
+            .into_iter()
+            .filter(|(id, _value)| Self::check_utxo_maturity(tx, params, *id).is_ok())
+            .collect();
+        if let Some(exact) = candidates.iter().find(|(_id, value)| *value == target) {
+            return Ok(vec![*exact]);
+        }
+        let mut selected = vec![];
+        let mut total: u64 = 0;
+        for (id, value) in candidates {
+            if total >= target {
+                break;
+            }
+            total += value;
+            selected.push((id, value));
+        }
+        if total < target {
+            return Err(miette!(
+                "insufficient transparent value: {total} is spendable, {target} is needed"
+            ));
+        }
+        Ok(selected)
+    }
+
+    /// Stage inputs covering `value` chosen by `select_utxos`, returning them.
+    pub fn spend_value(&mut self, value: u64) -> miette::Result<Vec<(u32, u64)>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let selected = Self::select_utxos(&tx, &self.params, value)?;
+        for (utxo_id, _value) in &selected {
+            tx.execute("INSERT INTO inputs (utxo_id) VALUES (?1)", [utxo_id])
+                .into_diagnostic()?;
+        }
+        tx.commit().into_diagnostic()?;
+        Ok(selected)
+    }
+
+    /// Reserve a utxo so that it can't be spent until it is unlocked.
+    pub fn lock_utxo(&self, utxo_id: u32) -> miette::Result<()> {
+        self.set_utxo_locked(utxo_id, true)
+    }
+
+    pub fn unlock_utxo(&self, utxo_id: u32) -> miette::Result<()> {
+        self.set_utxo_locked(utxo_id, false)
+    }
+
+    fn set_utxo_locked(&self, utxo_id: u32, locked: bool) -> miette::Result<()> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE utxos SET locked = ?1 WHERE id = ?2 AND spent_block IS NULL",
+                (locked, utxo_id),
+            )
             .into_diagnostic()?;
+        if updated == 0 {
+            return Err(miette!("utxo {utxo_id} does not exist or is spent"));
+        }
+        Ok(())
+    }
+
+    /// Conjured utxos were not created in any block, so they are always mature.
+    fn check_utxo_maturity(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        utxo_id: u32,
+    ) -> miette::Result<()> {
+        if params.transparent_maturity == 0 {
+            return Ok(());
+        }
+        let (created_block, mempool_tx): (Option<u32>, Option<u32>) = match tx.query_row(
+            "SELECT created_block, mempool_tx FROM utxos WHERE id = ?1",
+            [utxo_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(utxo) => utxo,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(miette!("utxo {utxo_id} does not exist"));
+            }
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        if mempool_tx.is_some() {
+            return Err(miette!(
+                "utxo {utxo_id} is immature, it is created by a pending transaction"
+            ));
+        }
+        if let Some(created_block) = created_block {
+            let tip_height = Self::get_next_block_id(tx)? - 1;
+            let mature_height = created_block + params.transparent_maturity;
+            if tip_height < mature_height {
+                return Err(miette!(
+                    "utxo {utxo_id} is immature, it can be spent once block {mature_height} is mined"
+                ));
+            }
+        }
         Ok(())
     }
 
-    pub fn create_note(&mut self, recipient: Option<String>, value: u64) -> miette::Result<()> {
+    /// Without a recipient the note pays a new address of `account`.
+    pub fn create_note(
+        &mut self,
+        account: u32,
+        recipient: Option<String>,
+        value: u64,
+        memo: Option<[u8; 512]>,
+    ) -> miette::Result<()> {
         let recipient = match recipient {
             Some(recipient) => {
-                let recipient = bs58::decode(recipient).into_vec().into_diagnostic()?;
-                let recipient: [u8; 43] = recipient
-                    .try_into()
-                    .map_err(|_err| miette!("wrong address length"))?;
-                let _ = Address::from_raw_address_bytes(&recipient)
-                    .expect("subtle error, failed to construct shielded address from raw bytes");
-                recipient
+                decode_shielded_address(&self.params, &recipient)?.to_raw_address_bytes()
             }
             None => {
-                let recipient = self.get_new_address()?;
+                let recipient = self.get_new_address(account)?;
                 recipient.to_raw_address_bytes()
             }
         };
         self.conn
             .execute(
-                "INSERT INTO shielded_outputs (recipient, value) VALUES (?1, ?2)",
-                (recipient, value),
+                "INSERT INTO shielded_outputs (recipient, value, memo) VALUES (?1, ?2, ?3)",
+                (recipient, value, memo.as_ref().map(|memo| &memo[..])),
+            )
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Stage a note paying `value` back to `account` at a fresh internal address.
+    pub fn create_change_note(
+        &mut self,
+        account: u32,
+        value: u64,
+        memo: Option<[u8; 512]>,
+    ) -> miette::Result<()> {
+        let recipient = self.next_change_address(account)?;
+        self.conn
+            .execute(
+                "INSERT INTO shielded_outputs (recipient, value, memo) VALUES (?1, ?2, ?3)",
+                (
+                    recipient.to_raw_address_bytes(),
+                    value,
+                    memo.as_ref().map(|memo| &memo[..]),
+                ),
             )
             .into_diagnostic()?;
         Ok(())
@@ -217,135 +703,312 @@ impl Db {
         Ok(())
     }
 
-    pub fn get_bundle_anchor(tx: &rusqlite::Transaction) -> miette::Result<Anchor> {
+    /// Height of the block whose note commitment tree bundles are anchored to,
+    /// `params.anchor_depth` blocks below the tip. `None` while the chain is too short to have
+    /// one, in which case bundles are anchored to the empty tree.
+    fn get_anchor_height(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+    ) -> miette::Result<Option<u32>> {
+        let tip = Self::get_next_block_id(tx)? - 1;
+        Ok(tip
+            .checked_sub(params.anchor_depth)
+            .filter(|height| *height > 0))
+    }
+
+    pub fn get_bundle_anchor(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+    ) -> miette::Result<Anchor> {
         // We need an anchor that is a few blocks old in order to construct an Orchard bundle.
-        let anchor = match tx.query_row(
-            "SELECT frontier FROM blocks ORDER BY id DESC LIMIT 1 OFFSET 3",
-            [],
-            |row| {
-                let frontier_bytes: Option<Vec<u8>> = row.get(0)?;
-                Ok(frontier_bytes)
-            },
-        ) {
-            Ok(frontier_bytes) => {
-                if let Some(frontier_bytes) = frontier_bytes {
-                    let (position, leaf, ommers): (u64, MerkleHashOrchard, Vec<MerkleHashOrchard>) =
-                        bincode::deserialize(&frontier_bytes).into_diagnostic()?;
-                    let position = Position::from(position);
-                    let frontier = NonEmptyFrontier::from_parts(position, leaf, ommers)
-                        .expect("failed to construct frontier from parts");
-                    let anchor: Anchor = frontier.root(Some(Level::from(32))).into();
-                    anchor
-                } else {
-                    Anchor::empty_tree()
-                }
+        let Some(anchor_height) = Self::get_anchor_height(tx, params)? else {
+            return Ok(Anchor::empty_tree());
+        };
+        let frontier_bytes: Option<Vec<u8>> = tx
+            .query_row(
+                "SELECT frontier FROM blocks WHERE id = ?1",
+                [anchor_height],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        let anchor = match frontier_bytes {
+            Some(frontier_bytes) => {
+                let frontier = deserialize_frontier(&frontier_bytes)?;
+                Anchor::from(frontier.root(Some(Level::from(NOTE_COMMITMENT_TREE_DEPTH))))
             }
-            Err(rusqlite::Error::QueryReturnedNoRows) => Anchor::empty_tree(),
-            Err(err) => return Err(err).into_diagnostic(),
+            None => Anchor::empty_tree(),
         };
         Ok(anchor)
     }
 
-    pub fn get_note(
+    pub fn get_note(tx: &rusqlite::Transaction, note_id: u32) -> miette::Result<Note> {
+        match tx.query_row(
+            "SELECT recipient, value, rho, rseed FROM notes WHERE id = ?1",
+            [note_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ) {
+            Ok((recipient, value, rho, rseed)) => {
+                note_from_parts(note_id, recipient, value, rho, rseed)
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                Err(miette!("note {note_id} does not exist"))
+            }
+            Err(err) => Err(err).into_diagnostic(),
+        }
+    }
+
+    /// Witness for `note` built by replaying the note commitments of every block up to and
+    /// including `last_block`, `None` if the note isn't in those blocks.
+    fn replay_witness(
+        tx: &rusqlite::Transaction,
+        note: &Note,
+        last_block: u32,
+    ) -> miette::Result<Option<IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>>>
+    {
+        let note_cmx = ExtractedNoteCommitment::from(note.commitment());
+        let mut tree = CommitmentTree::<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>::empty();
+        let mut witness: Option<IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>> =
+            None;
+        Self::for_each_block(tx, |block_id, block| {
+            if block_id > last_block {
+                return Ok(());
+            }
+            for cmx in &block.extracted_note_commitments() {
+                let leaf = MerkleHashOrchard::from_cmx(cmx);
+                match &mut witness {
+                    Some(witness) => witness.append(leaf),
+                    None => {
+                        let appended = tree.append(leaf);
+                        if *cmx == note_cmx {
+                            witness = Some(IncrementalWitness::from_tree(tree.clone()));
+                        }
+                        appended
+                    }
+                }
+                .map_err(|_err| miette!("note commitment tree is full"))?;
+            }
+            Ok(())
+        })?;
+        Ok(witness)
+    }
+
+    /// Merkle path of the wallet note `note_id` to the anchor returned by `get_bundle_anchor`.
+    /// The stored witness is taken when the note is mined, so the path is rebuilt from the chain
+    /// at the anchor block instead.
+    pub fn get_spend_path(
         tx: &rusqlite::Transaction,
+        params: &NetworkParams,
         note_id: u32,
-    ) -> miette::Result<(Note, orchard::tree::MerklePath)> {
-        let (recipient, value, rho, rseed, witness) = tx
+        note: &Note,
+    ) -> miette::Result<orchard::tree::MerklePath> {
+        let anchor_block = Self::get_anchor_height(tx, params)?.ok_or_else(|| {
+            miette!(
+                "note {note_id} can't be spent until the chain is {} blocks past it",
+                params.anchor_depth
+            )
+        })?;
+        let witness = Self::replay_witness(tx, note, anchor_block)?.ok_or_else(|| {
+            miette!(
+                "note {note_id} isn't in the chain up to the anchor block {anchor_block}, mine {} more blocks after it",
+                params.anchor_depth
+            )
+        })?;
+        let path = witness
+            .path()
+            .ok_or_else(|| miette!("failed to compute the merkle path of note {note_id}"))?;
+        Ok(orchard::tree::MerklePath::from(path))
+    }
+
+    /// Compare the merkle path a spend of wallet note `note_id` would use, and its stored
+    /// witness, against the current bundle anchor.
+    pub fn diagnose_spend(&mut self, note_id: u32) -> miette::Result<SpendDiagnosis> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let note = Self::get_note(&tx, note_id)?;
+        let (witness, created_block): (Vec<u8>, Option<u32>) = tx
             .query_row(
-                "SELECT recipient, value, rho, rseed, witness FROM notes WHERE id = ?1",
+                "SELECT witness, created_block FROM notes WHERE id = ?1",
                 [note_id],
-                |row| {
-                    let recipient: Vec<u8> = row.get(0)?;
-                    let value: u64 = row.get(1)?;
-                    let rho: Vec<u8> = row.get(2)?;
-                    let rseed: Vec<u8> = row.get(3)?;
-                    let witness: Vec<u8> = row.get(4)?;
-                    Ok((recipient, value, rho, rseed, witness))
-                },
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .into_diagnostic()?;
-        let recipient: [u8; 43] = recipient
-            .try_into()
-            .expect("wrong recipient address length");
-        let recipient = Address::from_raw_address_bytes(&recipient)
-            .expect("subtle error, failed to construct address from bytes");
-        let value = NoteValue::from_raw(value);
-        let rho: [u8; 32] = rho.try_into().expect("wrong rho length");
-        let rho = Rho::from_bytes(&rho).expect("subtle error, failed to construct rho from bytes");
-        let rseed: [u8; 32] = rseed.try_into().expect("wrong rseed length");
-        let rseed = RandomSeed::from_bytes(rseed, &rho)
-            .expect("subtle error, failed to construct rseed from bytes");
-        let (position, auth_path): (u32, [MerkleHashOrchard; 32]) =
-            bincode::deserialize(&witness).into_diagnostic()?;
-        let witness = orchard::tree::MerklePath::from_parts(position, auth_path);
-        let note = Note::from_parts(recipient, value, rho, rseed)
-            .expect("subtle error, failed to construct note from parts");
-        Ok((note, witness))
+        let witness = deserialize_incremental_witness(&witness)
+            .map_err(|err| miette!("note {note_id} has a malformed witness: {err}"))?;
+        let anchor_height = Self::get_anchor_height(&tx, &self.params)?;
+        let anchor = Self::get_bundle_anchor(&tx, &self.params)?;
+        let cmx = ExtractedNoteCommitment::from(note.commitment());
+        let path_root = match anchor_height {
+            Some(anchor_height) => Self::replay_witness(&tx, &note, anchor_height)?
+                .and_then(|witness| witness.path())
+                .map(|path| orchard::tree::MerklePath::from(path).root(cmx).to_bytes()),
+            None => None,
+        };
+        Ok(SpendDiagnosis {
+            note_id,
+            position: u64::from(witness.witnessed_position()),
+            created_block,
+            anchor_height,
+            anchor: anchor.to_bytes(),
+            witness_root: Anchor::from(witness.root()).to_bytes(),
+            path_root,
+        })
     }
 
-    pub fn clear_transaction(&mut self) -> miette::Result<()> {
+    /// Nullifier revealed when the wallet note `note_id` is spent.
+    pub fn note_nullifier(&mut self, note_id: u32) -> miette::Result<[u8; 32]> {
         let tx = self.conn.transaction().into_diagnostic()?;
-        tx.execute("DELETE FROM inputs", []).into_diagnostic()?;
-        tx.execute("DELETE FROM outputs", []).into_diagnostic()?;
-        tx.execute("DELETE FROM shielded_inputs", [])
-            .into_diagnostic()?;
-        tx.execute("DELETE FROM shielded_outputs", [])
+        let note = Self::get_note(&tx, note_id)?;
+        let sk = Self::get_sk(&tx, Self::get_note_account(&tx, note_id)?)?;
+        let fvk = orchard::keys::FullViewingKey::from(&sk);
+        Ok(note.nullifier(&fvk).to_bytes())
+    }
+
+    /// Wallet note `note_id` as a JSON `SpendableNote`.
+    pub fn export_spendable_note(&self, note_id: u32) -> miette::Result<String> {
+        let note = match self.conn.query_row(
+            "SELECT recipient, value, rho, rseed, witness FROM notes WHERE id = ?1",
+            [note_id],
+            |row| {
+                Ok(SpendableNote {
+                    recipient: hex::encode(row.get::<_, Vec<u8>>(0)?),
+                    value: row.get(1)?,
+                    rho: hex::encode(row.get::<_, Vec<u8>>(2)?),
+                    rseed: hex::encode(row.get::<_, Vec<u8>>(3)?),
+                    witness: hex::encode(row.get::<_, Vec<u8>>(4)?),
+                })
+            },
+        ) {
+            Ok(note) => note,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(miette!("note {note_id} does not exist"));
+            }
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        serde_json::to_string_pretty(&note).into_diagnostic()
+    }
+
+    /// Store a note exported by `export_spendable_note`. The note must belong to this wallet
+    /// and must not be spent or already stored.
+    pub fn import_spendable_note(&mut self, json: &str) -> miette::Result<()> {
+        let spendable: SpendableNote = serde_json::from_str(json).into_diagnostic()?;
+        let decode = |field: &str, value: &str| -> miette::Result<[u8; 32]> {
+            hex::decode(value)
+                .into_diagnostic()?
+                .try_into()
+                .map_err(|_err| miette!("wrong {field} length"))
+        };
+        let recipient: [u8; 43] = hex::decode(&spendable.recipient)
+            .into_diagnostic()?
+            .try_into()
+            .map_err(|_err| miette!("wrong recipient address length"))?;
+        let recipient = Option::from(Address::from_raw_address_bytes(&recipient))
+            .ok_or_else(|| miette!("invalid recipient address"))?;
+        let rho = Option::from(Rho::from_bytes(&decode("rho", &spendable.rho)?))
+            .ok_or_else(|| miette!("invalid rho"))?;
+        let rseed = Option::from(RandomSeed::from_bytes(
+            decode("rseed", &spendable.rseed)?,
+            &rho,
+        ))
+        .ok_or_else(|| miette!("invalid rseed"))?;
+        let note: Note = Option::from(Note::from_parts(
+            recipient,
+            NoteValue::from_raw(spendable.value),
+            rho,
+            rseed,
+        ))
+        .ok_or_else(|| miette!("invalid note"))?;
+        let witness =
+            deserialize_incremental_witness(&hex::decode(&spendable.witness).into_diagnostic()?)?;
+
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let mut owner = None;
+        for account in Self::get_accounts(&tx)? {
+            let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(&tx, account)?);
+            if fvk.scope_for_address(&recipient).is_some() {
+                owner = Some((account, fvk));
+                break;
+            }
+        }
+        let (account, fvk) = owner.ok_or_else(|| miette!("note does not belong to this wallet"))?;
+        let nullifier = note.nullifier(&fvk);
+        if Self::nullifier_exists(&tx, &nullifier)? {
+            return Err(miette!("nullifier exists, note is already spent"));
+        }
+        let stored: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM notes WHERE nullifier = ?1)",
+                [nullifier.to_bytes()],
+                |row| row.get(0),
+            )
             .into_diagnostic()?;
+        if stored {
+            return Err(miette!("note is already in the wallet"));
+        }
+        Self::store_note(&tx, account, &note, &witness, None, None)?;
         tx.commit().into_diagnostic()?;
         Ok(())
     }
 
-    pub fn submit_transaction(&mut self) -> miette::Result<()> {
+    /// Recompute the witness of wallet note `note_id` by replaying the note commitments of all
+    /// blocks, replacing the stored one. Returns the root the new witness resolves to, which is
+    /// the anchor of the chain tip.
+    pub fn rebuild_witness(&mut self, note_id: u32) -> miette::Result<[u8; 32]> {
         let tx = self.conn.transaction().into_diagnostic()?;
-        let anchor: Anchor = Self::get_bundle_anchor(&tx)?;
-        dbg!(&anchor);
-        let mut builder = orchard::builder::Builder::new(
-            BundleType::Transactional {
-                flags: Flags::ENABLED,
-                bundle_required: false,
-            },
-            anchor,
-        );
-        let shielded_inputs = Self::get_shielded_inputs(&tx)?;
-        let (_note, one_witness) = Self::get_note(&tx, shielded_inputs[0])?;
-        for note_id in shielded_inputs {
-            let (note, witness) = Self::get_note(&tx, note_id)?;
-            dbg!(note_id, &witness.root(note.commitment().into()));
-            dbg!(note_id, &one_witness.root(note.commitment().into()));
-            let sk = Self::get_sk(&tx)?;
-            let fvk = orchard::keys::FullViewingKey::from(&sk);
-            println!("here");
-            let err = builder.add_spend(fvk, note, witness);
-            dbg!(&err);
-            // err.into_diagnostic()?;
-        }
-        panic!();
-        let shielded_outputs = Self::get_shielded_outputs(&tx)?;
-        for (recipient, value) in shielded_outputs {
-            let recipient: [u8; 43] = recipient
-                .try_into()
-                .map_err(|_err| miette!("wrong address length"))?;
-            let recipient = Address::from_raw_address_bytes(&recipient).unwrap();
-            let value = NoteValue::from_raw(value);
-            builder
-                .add_output(None, recipient, value, None)
-                .into_diagnostic()?;
-        }
-
-        let rng = rand::rngs::StdRng::from_entropy();
-        let bundle = builder.build::<i64>(rng).into_diagnostic()?;
-
-        let inputs = Self::get_inputs(&tx)?;
-        let outputs = Self::get_outputs(&tx)?;
-        let transaction = crate::types::Transaction::from_bundle(inputs, outputs, &bundle);
-
-        let transaction_bytes = bincode::serialize(&transaction).into_diagnostic()?;
-
+        let note = Self::get_note(&tx, note_id)?;
+        let witness = Self::replay_witness(&tx, &note, u32::MAX)?;
+        let witness =
+            witness.ok_or_else(|| miette!("note {note_id} commitment is not in the chain"))?;
         tx.execute(
-            "INSERT INTO transactions (tx) VALUES (?1)",
-            (&transaction_bytes,),
+            "UPDATE notes SET witness = ?1 WHERE id = ?2",
+            (serialize_incremental_witness(&witness)?, note_id),
         )
         .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+        Ok(witness.root().to_bytes())
+    }
+
+    /// Unspent notes in the chain that decrypt with the wallet keys but aren't in the notes
+    /// table, as block id and value. Spent notes are skipped, so notes removed by
+    /// `prune_spent_notes` aren't reported.
+    pub fn find_missing_notes(&mut self) -> miette::Result<Vec<(u32, u64)>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let fvks = Self::get_accounts(&tx)?
+            .into_iter()
+            .map(|account| {
+                let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(&tx, account)?);
+                Ok((account, fvk))
+            })
+            .collect::<miette::Result<HashMap<u32, _>>>()?;
+        let mut missing = vec![];
+        Self::for_each_block(&tx, |block_id, block| {
+            for (account, note, _memo) in Self::get_notes_with_memos(&tx, &block)? {
+                if Self::nullifier_exists(&tx, &note.nullifier(&fvks[&account]))? {
+                    continue;
+                }
+                let tracked: bool = tx
+                    .query_row(
+                        "SELECT EXISTS(SELECT 1 FROM notes WHERE rho = ?1)",
+                        [note.rho().to_bytes()],
+                        |row| row.get(0),
+                    )
+                    .into_diagnostic()?;
+                if !tracked {
+                    missing.push((block_id, note.value().inner()));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(missing)
+    }
+
+    pub fn is_nullifier_spent(&mut self, nullifier: &[u8; 32]) -> miette::Result<bool> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let nullifier = Option::from(Nullifier::from_bytes(nullifier))
+            .ok_or_else(|| miette!("invalid nullifier"))?;
+        Self::nullifier_exists(&tx, &nullifier)
+    }
+
+    pub fn clear_transaction(&mut self) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
         tx.execute("DELETE FROM inputs", []).into_diagnostic()?;
         tx.execute("DELETE FROM outputs", []).into_diagnostic()?;
         tx.execute("DELETE FROM shielded_inputs", [])
@@ -353,29 +1016,345 @@ impl Db {
         tx.execute("DELETE FROM shielded_outputs", [])
             .into_diagnostic()?;
         tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Returns the ids of the pending utxos created by the transaction. They can be spent by
+    /// other pending transactions before this one is mined.
+    pub fn submit_transaction(&mut self) -> miette::Result<Vec<u32>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let pending_utxos = Self::submit_staged(&tx, &self.params, &self.keys)?;
+        tx.commit().into_diagnostic()?;
+        Ok(pending_utxos)
+    }
+
+    /// Like `submit_transaction`, but first returns the change of the staged transparent inputs
+    /// after paying `fee` to a new utxo, see `stage_transparent_change`. Returns the change
+    /// value along with the pending utxo ids.
+    pub fn submit_transaction_with_change(
+        &mut self,
+        fee: u64,
+    ) -> miette::Result<(Option<u64>, Vec<u32>)> {
+        let min_fee = self.params.default_fee();
+        if fee < min_fee {
+            return Err(miette!("fee {fee} is below the minimum fee {min_fee}"));
+        }
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let change = Self::stage_transparent_change(&tx, fee)?;
+        let pending_utxos = Self::submit_staged(&tx, &self.params, &self.keys)?;
+        tx.commit().into_diagnostic()?;
+        Ok((change, pending_utxos))
+    }
+
+    fn submit_staged(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        keys: &CircuitKeys,
+    ) -> miette::Result<Vec<u32>> {
+        let mut builder = TransactionBuilder::new(tx, params)?;
+        for note_id in Self::get_shielded_inputs(tx)? {
+            builder.add_note_spend(tx, params, note_id)?;
+        }
+        for ShieldedOutput {
+            recipient,
+            value,
+            memo,
+        } in Self::get_shielded_outputs(tx)?
+        {
+            let recipient: [u8; 43] = recipient
+                .try_into()
+                .map_err(|_err| miette!("wrong address length"))?;
+            let recipient = Option::from(Address::from_raw_address_bytes(&recipient))
+                .ok_or_else(|| miette!("invalid staged shielded output recipient"))?;
+            builder.add_shielded_output(recipient, value, memo)?;
+        }
+        for utxo_id in Self::get_inputs(tx)? {
+            builder.add_transparent_input(utxo_id);
+        }
+        for output in Self::get_outputs(tx)? {
+            builder.add_transparent_output(output.value);
+        }
+        let transaction = builder.build(keys)?;
+        let pending_utxos = Self::add_to_mempool(tx, &transaction)?;
+        tx.execute("DELETE FROM inputs", []).into_diagnostic()?;
+        tx.execute("DELETE FROM outputs", []).into_diagnostic()?;
+        tx.execute("DELETE FROM shielded_inputs", [])
+            .into_diagnostic()?;
+        tx.execute("DELETE FROM shielded_outputs", [])
+            .into_diagnostic()?;
+        Ok(pending_utxos)
+    }
+
+    /// Suggested fee of a transaction with `n_inputs` transparent inputs, `n_outputs` transparent
+    /// outputs and `n_actions` Orchard actions, after ZIP 317:
+    ///
+    ///     logical_actions = max(n_inputs, n_outputs) + n_actions
+    ///     fee = marginal_fee * max(logical_actions, grace_actions)
+    ///
+    /// with `marginal_fee` and `grace_actions` taken from the network parameters. Transparent
+    /// inputs and outputs are counted one each rather than by their serialized size.
+    pub fn estimate_fee(&self, n_inputs: usize, n_outputs: usize, n_actions: usize) -> u64 {
+        let logical_actions = n_inputs.max(n_outputs) + n_actions;
+        self.params.marginal_fee * logical_actions.max(self.params.grace_actions) as u64
+    }
+
+    /// `estimate_fee` of the staged transaction, counting the Orchard actions its bundle will be
+    /// padded to.
+    pub fn estimate_pending_fee(&mut self) -> miette::Result<u64> {
+        let pending = self.pending_transaction_state()?;
+        let n_actions = BundleType::DEFAULT
+            .num_actions(
+                pending.shielded_inputs.len(),
+                pending.shielded_outputs.len(),
+            )
+            .map_err(|err| miette!("{err}"))?;
+        Ok(self.estimate_fee(pending.inputs.len(), pending.outputs.len(), n_actions))
+    }
+
+    /// Build and validate a transaction spending wallet note `note_id`, paying `amount` back to
+    /// the note's account with the rest minus `fee` as change, without submitting it. Fails with
+    /// the error a real spend would hit, such as the note not being deep enough in the chain.
+    pub fn dry_spend(&mut self, note_id: u32, amount: u64, fee: u64) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let value = Self::get_note(&tx, note_id)?.value().inner();
+        let change = value
+            .checked_sub(amount)
+            .and_then(|value| value.checked_sub(fee))
+            .ok_or_else(|| {
+                miette!("note {note_id} holds {value}, less than amount {amount} plus fee {fee}")
+            })?;
+        let sk = Self::get_sk(&tx, Self::get_note_account(&tx, note_id)?)?;
+        let fvk = orchard::keys::FullViewingKey::from(&sk);
 
+        let mut builder = TransactionBuilder::new(&tx, &self.params)?;
+        builder.add_note_spend(&tx, &self.params, note_id)?;
+        builder.add_shielded_output(fvk.address_at(0u32, zip32::Scope::External), amount, None)?;
+        if change > 0 {
+            builder.add_shielded_output(
+                fvk.address_at(0u32, zip32::Scope::Internal),
+                change,
+                None,
+            )?;
+        }
+        let transaction = builder.build(&self.keys)?;
+        Self::validate_transaction(&tx, &self.params, &self.keys, &transaction)?;
+        // Nothing is written, dropping `tx` leaves the database as it was.
         Ok(())
     }
 
+    /// Submit a transaction built with `TransactionBuilder` to the mempool, leaving the staged
+    /// transaction alone. Returns the ids of the pending utxos of its outputs.
+    pub fn submit_built(
+        &mut self,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<Vec<u32>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let pending_utxos = Self::add_to_mempool(&tx, transaction)?;
+        tx.commit().into_diagnostic()?;
+        Ok(pending_utxos)
+    }
+
+    fn add_to_mempool(
+        tx: &rusqlite::Transaction,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<Vec<u32>> {
+        let txid = transaction.txid();
+        for (mempool_id, mempool_transaction) in Self::get_transactions(tx)? {
+            if mempool_transaction.txid() == txid {
+                return Err(miette!(
+                    "transaction {} already in mempool with id {mempool_id}",
+                    hex::encode(txid)
+                ));
+            }
+        }
+
+        let transaction_bytes = bincode::serialize(transaction).into_diagnostic()?;
+
+        tx.execute(
+            "INSERT INTO transactions (tx) VALUES (?1)",
+            (&transaction_bytes,),
+        )
+        .into_diagnostic()?;
+        let mempool_id = tx.last_insert_rowid();
+        let mut pending_utxos = vec![];
+        for output in &transaction.outputs {
+            tx.execute(
+                "INSERT INTO utxos (value, mempool_tx) VALUES (?1, ?2)",
+                (output.value, mempool_id),
+            )
+            .into_diagnostic()?;
+            pending_utxos.push(tx.last_insert_rowid() as u32);
+        }
+        Ok(pending_utxos)
+    }
+
     fn get_transactions(
         tx: &rusqlite::Transaction,
-    ) -> miette::Result<Vec<crate::types::Transaction>> {
+    ) -> miette::Result<Vec<(u32, crate::types::Transaction)>> {
         let mut statement = tx
-            .prepare("SELECT tx FROM transactions")
+            .prepare("SELECT id, tx FROM transactions ORDER BY id")
             .into_diagnostic()?;
-        let transactions: Vec<Vec<u8>> = statement
-            .query_map([], |row| Ok(row.get(0)?))
+        let transactions: Vec<(u32, Vec<u8>)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
-        let transactions: Vec<crate::types::Transaction> = transactions
+        let transactions: Vec<(u32, crate::types::Transaction)> = transactions
             .iter()
-            .map(|bytes| bincode::deserialize(bytes))
-            .collect::<Result<_, _>>()
+            .map(|(id, bytes)| Ok((*id, bincode::deserialize(bytes)?)))
+            .collect::<Result<_, bincode::Error>>()
             .into_diagnostic()?;
         Ok(transactions)
     }
 
+    /// Transactions waiting in the mempool with their mempool ids, in submission order.
+    pub fn get_pending_transactions(
+        &mut self,
+    ) -> miette::Result<Vec<(u32, crate::types::Transaction)>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::get_transactions(&tx)
+    }
+
+    /// Every mempool transaction with the txids of the mempool transactions whose pending
+    /// utxos it spends, in submission order.
+    pub fn mempool_dependencies(&mut self) -> miette::Result<Vec<MempoolDependency>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let transactions = Self::get_transactions(&tx)?;
+        let txids: HashMap<u32, [u8; 32]> = transactions
+            .iter()
+            .map(|(id, transaction)| (*id, transaction.txid()))
+            .collect();
+        let mut dependencies = vec![];
+        for (id, transaction) in &transactions {
+            let parents = Self::get_mempool_parents(&tx, transaction)?
+                .iter()
+                .filter_map(|parent| txids.get(parent).copied())
+                .collect();
+            dependencies.push(MempoolDependency {
+                txid: txids[id],
+                parents,
+            });
+        }
+        Ok(dependencies)
+    }
+
+    /// Attach `label` to the transaction with `txid`, replacing any previous label. Labels are
+    /// keyed by txid rather than stored on the mempool row, so they survive mining.
+    pub fn label_transaction(&self, txid: &[u8; 32], label: &str) -> miette::Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO transaction_labels (txid, label) VALUES (?1, ?2)",
+                (txid, label),
+            )
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    pub fn get_transaction_label(&self, txid: &[u8; 32]) -> miette::Result<Option<String>> {
+        match self.conn.query_row(
+            "SELECT label FROM transaction_labels WHERE txid = ?1",
+            [txid],
+            |row| row.get(0),
+        ) {
+            Ok(label) => Ok(Some(label)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err).into_diagnostic(),
+        }
+    }
+
+    /// Ids of the mempool transactions whose pending utxos are spent by `transaction`.
+    fn get_mempool_parents(
+        tx: &rusqlite::Transaction,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<Vec<u32>> {
+        let mut parents = vec![];
+        for input in &transaction.inputs {
+            let mempool_tx: Option<u32> = match tx.query_row(
+                "SELECT mempool_tx FROM utxos WHERE id = ?1",
+                [input],
+                |row| row.get(0),
+            ) {
+                Ok(mempool_tx) => mempool_tx,
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(err) => return Err(err).into_diagnostic(),
+            };
+            if let Some(mempool_tx) = mempool_tx {
+                if !parents.contains(&mempool_tx) {
+                    parents.push(mempool_tx);
+                }
+            }
+        }
+        Ok(parents)
+    }
+
+    /// Mempool transactions can spend the pending utxos of other mempool transactions, so they
+    /// are ordered such that every parent is connected before its children. Otherwise
+    /// transactions keep their submission order.
+    fn sort_by_dependencies(
+        tx: &rusqlite::Transaction,
+        transactions: Vec<(u32, crate::types::Transaction)>,
+    ) -> miette::Result<Vec<(u32, crate::types::Transaction)>> {
+        let mut remaining = vec![];
+        for (id, transaction) in transactions {
+            let parents = Self::get_mempool_parents(tx, &transaction)?;
+            remaining.push((id, parents, transaction));
+        }
+        let mut sorted_ids = vec![];
+        let mut sorted = vec![];
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|(_id, parents, _transaction)| {
+                parents.iter().all(|parent| {
+                    sorted_ids.contains(parent)
+                        || !remaining.iter().any(|(other, _, _)| other == parent)
+                })
+            });
+            let Some(ready) = ready else {
+                return Err(miette!("pending transactions have cyclic dependencies"));
+            };
+            let (id, _parents, transaction) = remaining.remove(ready);
+            sorted_ids.push(id);
+            sorted.push((id, transaction));
+        }
+        Ok(sorted)
+    }
+
+    /// Outputs of a transaction from our own mempool already have pending utxos, which may be
+    /// spent by other pending transactions, so those get confirmed instead of new utxos being
+    /// created.
+    fn get_pending_utxos(
+        tx: &rusqlite::Transaction,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<Vec<u32>> {
+        let transaction_bytes = bincode::serialize(transaction).into_diagnostic()?;
+        let mut statement = tx
+            .prepare(
+                "SELECT id FROM utxos WHERE mempool_tx =
+                    (SELECT id FROM transactions WHERE tx = ?1 ORDER BY id LIMIT 1)
+                ORDER BY id",
+            )
+            .into_diagnostic()?;
+        let utxos: Vec<u32> = statement
+            .query_map([transaction_bytes], |row| row.get(0))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        Ok(utxos)
+    }
+
+    /// Number of note commitments in the note commitment tree, whoever the notes belong to.
+    pub fn tree_size(&mut self) -> miette::Result<u64> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::get_tree_size(&tx)
+    }
+
+    fn get_tree_size(tx: &rusqlite::Transaction) -> miette::Result<u64> {
+        let tree_size = match Self::get_last_frontier(tx)? {
+            Some(frontier) => u64::from(frontier.position()) + 1,
+            None => 0,
+        };
+        Ok(tree_size)
+    }
+
     fn get_last_frontier(
         tx: &rusqlite::Transaction,
     ) -> miette::Result<Option<NonEmptyFrontier<MerkleHashOrchard>>> {
@@ -396,23 +1375,73 @@ impl Db {
             }
         };
 
-        let (position, leaf, ommers): (u64, MerkleHashOrchard, Vec<MerkleHashOrchard>) =
-            bincode::deserialize(&frontier).into_diagnostic()?;
-        let position = Position::from(position);
-        let frontier = NonEmptyFrontier::from_parts(position, leaf, ommers)
-            .expect("failed to reconstruct frontier");
-        Ok(Some(frontier))
+        Ok(Some(deserialize_frontier(&frontier)?))
+    }
+
+    /// Check that the position of the tip frontier accounts for exactly the note commitments
+    /// of the stored blocks, so a block is never mined on top of a frontier that has drifted
+    /// from the chain.
+    pub fn check_frontier_consistency(tx: &rusqlite::Transaction) -> miette::Result<()> {
+        let mut commitments: u64 = 0;
+        Self::for_each_block(tx, |_block_id, block| {
+            commitments += block.extracted_note_commitments().len() as u64;
+            Ok(())
+        })?;
+        let frontier_size = match Self::get_last_frontier(tx)? {
+            Some(frontier) => u64::from(frontier.position()) + 1,
+            None => 0,
+        };
+        if frontier_size != commitments {
+            return Err(miette!(
+                "tip frontier holds {frontier_size} note commitments but the chain has \
+                {commitments}, refusing to mine on an inconsistent note commitment tree"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_timestamp(tx: &rusqlite::Transaction, timestamp: i64) -> miette::Result<()> {
+        if let Some(previous_timestamp) = Self::get_last_timestamp(tx)? {
+            if timestamp < previous_timestamp {
+                return Err(miette!(
+                    "block timestamp {timestamp} is earlier than the previous block timestamp {previous_timestamp}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_last_timestamp(tx: &rusqlite::Transaction) -> miette::Result<Option<i64>> {
+        match tx.query_row(
+            "SELECT timestamp FROM blocks ORDER BY id DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(timestamp) => Ok(timestamp),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err).into_diagnostic(),
+        }
     }
 
-    fn insert_nullifier(tx: &rusqlite::Transaction, nullifier: &Nullifier) -> miette::Result<()> {
+    fn insert_nullifier(
+        tx: &rusqlite::Transaction,
+        nullifier: &Nullifier,
+        block_id: u32,
+    ) -> miette::Result<()> {
         tx.execute(
-            "INSERT INTO nullifiers (nullifier) VALUES (?1)",
-            [nullifier.to_bytes()],
+            "INSERT INTO nullifiers (nullifier, block_id) VALUES (?1, ?2)",
+            (nullifier.to_bytes(), block_id),
         )
         .into_diagnostic()?;
         Ok(())
     }
 
+    /// Remove the nullifiers revealed in block `block_id`, returning how many were removed.
+    fn remove_block_nullifiers(tx: &rusqlite::Transaction, block_id: u32) -> miette::Result<usize> {
+        tx.execute("DELETE FROM nullifiers WHERE block_id = ?1", [block_id])
+            .into_diagnostic()
+    }
+
     fn nullifier_exists(tx: &rusqlite::Transaction, nullifier: &Nullifier) -> miette::Result<bool> {
         let nullifier = nullifier.to_bytes();
         let nullifier_exists = match tx.query_row(
@@ -432,233 +1461,1402 @@ impl Db {
         Ok(nullifier_exists)
     }
 
-    fn store_block(
-        tx: &rusqlite::Transaction,
-        frontier: Option<NonEmptyFrontier<MerkleHashOrchard>>,
-        fee: u64,
-        block: &Block,
-    ) -> miette::Result<()> {
-        let frontier_bytes = match frontier {
-            Some(frontier) => {
-                let (position, leaf, ommers) = frontier.into_parts();
-                let position: u64 = position.into();
-                let frontier_bytes =
-                    bincode::serialize(&(position, leaf, ommers)).into_diagnostic()?;
-                Some(frontier_bytes)
-            }
-            None => None,
-        };
-        let block_bytes = bincode::serialize(block).into_diagnostic()?;
-        tx.execute(
-            "INSERT INTO blocks (fee, frontier, block) VALUES (?1, ?2, ?3)",
-            (fee, frontier_bytes, block_bytes),
-        )
-        .into_diagnostic()?;
-        Ok(())
+    /// Blocks are numbered from 1, so the id of the next block is also its height.
+    fn get_next_block_id(tx: &rusqlite::Transaction) -> miette::Result<u32> {
+        let block_id = tx
+            .query_row("SELECT COALESCE(MAX(id), 0) + 1 FROM blocks", [], |row| {
+                row.get(0)
+            })
+            .into_diagnostic()?;
+        Ok(block_id)
     }
 
-    fn clear_transactions(tx: &rusqlite::Transaction) -> miette::Result<()> {
-        tx.execute("DELETE FROM transactions", [])
+    /// Every nullifier in the nullifier set with the block it was revealed in, `None` for
+    /// nullifiers stored before blocks were recorded.
+    pub fn all_nullifiers(&self) -> miette::Result<Vec<([u8; 32], Option<u32>)>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT nullifier, block_id FROM nullifiers ORDER BY id")
             .into_diagnostic()?;
-        Ok(())
+        let nullifiers: Vec<(Vec<u8>, Option<u32>)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        let nullifiers = nullifiers
+            .into_iter()
+            .map(|(nullifier, block_id)| {
+                let nullifier: [u8; 32] = nullifier
+                    .try_into()
+                    .map_err(|_err| miette!("wrong nullifier length"))?;
+                Ok((nullifier, block_id))
+            })
+            .collect::<miette::Result<Vec<_>>>()?;
+        Ok(nullifiers)
     }
 
-    pub fn validate_transaction(
-        tx: &rusqlite::Transaction,
-        transaction: &crate::types::Transaction,
-    ) -> miette::Result<u64> {
-        let nullifiers = transaction.nullifiers();
-        for nullifier in &nullifiers {
-            if Self::nullifier_exists(&tx, nullifier)? {
-                return Err(miette!("nullifier exists, note is already spent"));
-            }
+    /// Hash of the block at height `block_id`, all zeroes for height 0 and for blocks stored
+    /// before block hashes were recorded.
+    fn get_block_hash(tx: &rusqlite::Transaction, block_id: u32) -> miette::Result<[u8; 32]> {
+        let hash: Option<Vec<u8>> =
+            match tx.query_row("SELECT hash FROM blocks WHERE id = ?1", [block_id], |row| {
+                row.get(0)
+            }) {
+                Ok(hash) => hash,
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(err) => return Err(err).into_diagnostic(),
+            };
+        match hash {
+            Some(hash) => hash
+                .try_into()
+                .map_err(|_err| miette!("wrong block hash length")),
+            None => Ok([0; 32]),
         }
-        let _bundle = {
-            let anchor = Self::get_bundle_anchor(tx)?;
-            transaction.to_bundle(anchor)
+    }
+
+    /// Height, hash and note commitment tree root of the chain tip.
+    pub fn tip_summary(&mut self) -> miette::Result<(u32, [u8; 32], [u8; 32])> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let height = Self::get_next_block_id(&tx)? - 1;
+        let hash = Self::get_block_hash(&tx, height)?;
+        let anchor = match Self::get_last_frontier(&tx)? {
+            Some(frontier) => {
+                Anchor::from(frontier.root(Some(Level::from(NOTE_COMMITMENT_TREE_DEPTH))))
+            }
+            None => Anchor::empty_tree(),
         };
+        Ok((height, hash, anchor.to_bytes()))
+    }
 
-        let mut value_in = 0;
-        for input in &transaction.inputs {
-            let value: i64 = tx
-                .query_row("SELECT value FROM utxos WHERE id = ?1", [input], |row| {
-                    row.get(0)
-                })
+    /// Ommer hashes of the note commitment tree frontier at the tip, from the lowest level up.
+    /// Empty if the tree is empty.
+    pub fn frontier_ommers(&mut self) -> miette::Result<Vec<[u8; 32]>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let ommers = match Self::get_last_frontier(&tx)? {
+            Some(frontier) => frontier
+                .ommers()
+                .iter()
+                .map(|ommer| ommer.to_bytes())
+                .collect(),
+            None => vec![],
+        };
+        Ok(ommers)
+    }
+
+    /// Compare the chains stored in two databases block by block. Only the blocks table is
+    /// read, so neither wallet has to be unlocked. Both files are opened read-only and are not
+    /// migrated.
+    pub fn compare_chains(
+        path: impl AsRef<Path>,
+        other_path: impl AsRef<Path>,
+    ) -> miette::Result<ChainComparison> {
+        fn block_hashes(path: impl AsRef<Path>) -> miette::Result<Vec<Option<Vec<u8>>>> {
+            let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
                 .into_diagnostic()?;
-            value_in += value;
+            let mut statement = conn
+                .prepare("SELECT hash FROM blocks ORDER BY id")
+                .into_diagnostic()?;
+            let hashes = statement
+                .query_map([], |row| row.get(0))
+                .into_diagnostic()?
+                .collect::<Result<Vec<_>, _>>()
+                .into_diagnostic()?;
+            Ok(hashes)
         }
+        let hashes = block_hashes(path)?;
+        let other_hashes = block_hashes(other_path)?;
+        let divergence_height = hashes
+            .iter()
+            .zip(&other_hashes)
+            .position(|(hash, other_hash)| hash.is_none() || hash != other_hash)
+            .map(|index| index as u32 + 1);
+        Ok(ChainComparison {
+            height: hashes.len() as u32,
+            other_height: other_hashes.len() as u32,
+            divergence_height,
+        })
+    }
 
-        let mut value_out = 0;
-        for output in &transaction.outputs {
-            value_out += output.value as i64;
+    /// Call `f` with every block in height order. Blocks are deserialized one at a time, so the
+    /// whole chain is never held in memory.
+    pub fn for_each_block(
+        tx: &rusqlite::Transaction,
+        mut f: impl FnMut(u32, Block) -> miette::Result<()>,
+    ) -> miette::Result<()> {
+        let mut statement = tx
+            .prepare("SELECT id, block FROM blocks ORDER BY id")
+            .into_diagnostic()?;
+        let mut rows = statement.query([]).into_diagnostic()?;
+        while let Some(row) = rows.next().into_diagnostic()? {
+            let block_id: u32 = row.get(0).into_diagnostic()?;
+            let block_bytes: Vec<u8> = row.get(1).into_diagnostic()?;
+            let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+            f(block_id, block)?;
         }
+        Ok(())
+    }
 
-        /*
-        A positive Orchard balancing value takes value from the Orchard transaction value pool and
-        adds it to the transparent transaction value pool. A negative Orchard balancing value does
-        the reverse. As a result, positive value_balance_orchard is treated like an input to the
-        transparent transaction value pool, whereas negative value_balance_orchard is treated like
-        an output from that pool.
-        */
-
-        let value_balance_orchard = transaction.value_balance_orchard;
+    pub fn get_block_count(&self) -> miette::Result<u32> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0))
+            .into_diagnostic()
+    }
 
-        let fee = value_in + value_balance_orchard - value_out;
-        if fee < 0 {
-            return Err(miette!("transaction fee is negative"));
-        }
+    /// Fee collected by block `block_id` along with the block, `None` if there is no such block.
+    pub fn get_block(&self, block_id: u32) -> miette::Result<Option<(u64, Block)>> {
+        let (fee, block_bytes): (u64, Vec<u8>) = match self.conn.query_row(
+            "SELECT fee, block FROM blocks WHERE id = ?1",
+            [block_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(row) => row,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+        Ok(Some((fee, block)))
+    }
 
-        Ok(fee as u64)
+    pub fn chain_summary(&mut self) -> miette::Result<ChainSummary> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let height = Self::get_next_block_id(&tx)? - 1;
+        let total_fees: u64 = tx
+            .query_row("SELECT COALESCE(SUM(fee), 0) FROM blocks", [], |row| {
+                row.get(0)
+            })
+            .into_diagnostic()?;
+        let mut transactions = 0;
+        Self::for_each_block(&tx, |_block_id, block| {
+            transactions += block.transactions.len() as u64;
+            Ok(())
+        })?;
+        Ok(ChainSummary {
+            height,
+            total_fees,
+            transactions,
+        })
     }
 
-    fn connect_block(
-        tx: &rusqlite::Transaction,
-        block: &Block,
-    ) -> miette::Result<(Option<NonEmptyFrontier<MerkleHashOrchard>>, u64)> {
-        // Updating transparent state.
-        let mut total_fee = 0;
-        for transaction in &block.transactions {
-            let fee = Self::validate_transaction(tx, transaction)?;
-            total_fee += fee;
-            for input in &transaction.inputs {
-                tx.execute("DELETE FROM utxos WHERE id = ?1", [input])
-                    .into_diagnostic()?;
-            }
-            for output in &transaction.outputs {
-                tx.execute("INSERT INTO utxos (value) VALUES (?1)", [output.value])
-                    .into_diagnostic()?;
+    /// Write every block with its timestamp and issuance to `path`, returning the number of
+    /// blocks exported.
+    pub fn export_chain(&mut self, path: impl AsRef<Path>) -> miette::Result<u32> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let mut file = BufWriter::new(File::create(path).into_diagnostic()?);
+        file.write_all(&[0; EXPORT_HEADER_LEN]).into_diagnostic()?;
+        let mut hasher = blake3::Hasher::new();
+        let mut count: u32 = 0;
+        let mut statement = tx
+            .prepare("SELECT COALESCE(timestamp, 0), issuance, block FROM blocks ORDER BY id")
+            .into_diagnostic()?;
+        let mut rows = statement.query([]).into_diagnostic()?;
+        while let Some(row) = rows.next().into_diagnostic()? {
+            let timestamp: i64 = row.get(0).into_diagnostic()?;
+            let issuance: u64 = row.get(1).into_diagnostic()?;
+            let block_bytes: Vec<u8> = row.get(2).into_diagnostic()?;
+            let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+            let entry = bincode::serialize(&(timestamp, issuance, block)).into_diagnostic()?;
+            hasher.update(&entry);
+            file.write_all(&entry).into_diagnostic()?;
+            count += 1;
+        }
+        file.seek(SeekFrom::Start(0)).into_diagnostic()?;
+        file.write_all(EXPORT_MAGIC).into_diagnostic()?;
+        file.write_all(&EXPORT_VERSION.to_le_bytes())
+            .into_diagnostic()?;
+        file.write_all(&count.to_le_bytes()).into_diagnostic()?;
+        file.write_all(hasher.finalize().as_bytes())
+            .into_diagnostic()?;
+        file.flush().into_diagnostic()?;
+        Ok(count)
+    }
+
+    /// Connect the blocks of a file written by `export_chain` on top of an empty chain,
+    /// returning the number of blocks imported. Conjured utxos aren't part of the chain, so
+    /// blocks spending them can't be imported.
+    pub fn import_chain(&mut self, path: impl AsRef<Path>) -> miette::Result<u32> {
+        let bytes = std::fs::read(path).into_diagnostic()?;
+        let corrupt = || miette!("corrupt or truncated export file");
+        if bytes.len() < EXPORT_HEADER_LEN || &bytes[..8] != EXPORT_MAGIC {
+            return Err(corrupt());
+        }
+        let (header, payload) = bytes.split_at(EXPORT_HEADER_LEN);
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != EXPORT_VERSION {
+            return Err(miette!("unsupported export file version {version}"));
+        }
+        let count = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if blake3::hash(payload).as_bytes() != &header[16..] {
+            return Err(corrupt());
+        }
+
+        let tx = self.conn.transaction().into_diagnostic()?;
+        if Self::get_next_block_id(&tx)? != 1 {
+            return Err(miette!(
+                "chains can only be imported into an empty database"
+            ));
+        }
+        let mut reader = payload;
+        for block_id in 1..=count {
+            let (timestamp, issuance, block): (i64, u64, Block) =
+                bincode::deserialize_from(&mut reader).map_err(|_err| corrupt())?;
+            let (frontier, total_fee) = Self::connect_block(
+                &tx,
+                &self.params,
+                &self.keys,
+                block_id,
+                timestamp,
+                issuance,
+                &block,
+            )?;
+            Self::store_block(
+                &tx, block_id, timestamp, issuance, frontier, total_fee, &block,
+            )?;
+        }
+        if !reader.is_empty() {
+            return Err(corrupt());
+        }
+        tx.commit().into_diagnostic()?;
+        Ok(count)
+    }
+
+    /// Hex encoded block at `height` with the hash of its parent, timestamp and issuance, for
+    /// replaying it into another database with `import_block`.
+    pub fn export_block(&mut self, height: u32) -> miette::Result<String> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let (timestamp, issuance, block_bytes): (i64, u64, Vec<u8>) = match tx.query_row(
+            "SELECT COALESCE(timestamp, 0), issuance, block FROM blocks WHERE id = ?1",
+            [height],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ) {
+            Ok(row) => row,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(miette!("block {height} does not exist"));
             }
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+        let previous_hash = Self::get_block_hash(&tx, height - 1)?;
+        let bytes =
+            bincode::serialize(&(previous_hash, timestamp, issuance, block)).into_diagnostic()?;
+        Ok(hex::encode(bytes))
+    }
+
+    /// Connect a block exported by `export_block` on top of the tip. The block must extend the
+    /// tip and pass the same validation as a mined block. Its transactions are removed from the
+    /// mempool. Returns the new height and tip hash.
+    pub fn import_block(&mut self, block_hex: &str) -> miette::Result<(u32, [u8; 32])> {
+        let bytes = hex::decode(block_hex.trim()).into_diagnostic()?;
+        let (previous_hash, timestamp, issuance, block): ([u8; 32], i64, u64, Block) =
+            bincode::deserialize(&bytes).map_err(|_err| miette!("malformed block encoding"))?;
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let block_id = Self::get_next_block_id(&tx)?;
+        let tip_hash = Self::get_block_hash(&tx, block_id - 1)?;
+        if previous_hash != tip_hash {
+            return Err(miette!(
+                "block doesn't extend the tip: its parent is {}, the tip is {}",
+                hex::encode(previous_hash),
+                hex::encode(tip_hash)
+            ));
         }
+        let (frontier, total_fee) = Self::connect_block(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            issuance,
+            &block,
+        )?;
+        Self::store_block(
+            &tx, block_id, timestamp, issuance, frontier, total_fee, &block,
+        )?;
+        for transaction in &block.transactions {
+            let transaction_bytes = bincode::serialize(transaction).into_diagnostic()?;
+            tx.execute(
+                "DELETE FROM transactions WHERE tx = ?1",
+                [transaction_bytes],
+            )
+            .into_diagnostic()?;
+        }
+        let hash = Self::get_block_hash(&tx, block_id)?;
+        tx.commit().into_diagnostic()?;
+        Ok((block_id, hash))
+    }
 
-        // Storing notes and corresponding merkle proofs.
-        {
-            let anchor = Self::get_bundle_anchor(tx)?;
-            let sk = Self::get_sk(tx)?;
-            let fvk = orchard::keys::FullViewingKey::from(&sk);
-            let ivk = fvk.to_ivk(zip32::Scope::External);
-            let keys = [ivk];
-            let mut notes = vec![];
-            for transaction in &block.transactions {
-                let bundle = transaction.to_bundle(anchor);
-                if let Some(bundle) = bundle {
-                    for (_action_index, _ivk, note, _address, _memo) in
-                        bundle.decrypt_outputs_with_keys(&keys)
-                    {
-                        notes.push(note);
+    fn store_block(
+        tx: &rusqlite::Transaction,
+        block_id: u32,
+        timestamp: i64,
+        issuance: u64,
+        frontier: Option<NonEmptyFrontier<MerkleHashOrchard>>,
+        fee: u64,
+        block: &Block,
+    ) -> miette::Result<()> {
+        let frontier_bytes = match frontier {
+            Some(frontier) => {
+                let (position, leaf, ommers) = frontier.into_parts();
+                let position: u64 = position.into();
+                let frontier_bytes =
+                    bincode::serialize(&(position, leaf, ommers)).into_diagnostic()?;
+                Some(frontier_bytes)
+            }
+            None => None,
+        };
+        let block_bytes = bincode::serialize(block).into_diagnostic()?;
+        let previous_hash = Self::get_block_hash(tx, block_id - 1)?;
+        let hash = block_hash(&previous_hash, timestamp, issuance, &block_bytes);
+        tx.execute(
+            "INSERT INTO blocks (id, timestamp, issuance, fee, frontier, block, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (
+                block_id,
+                timestamp,
+                issuance,
+                fee,
+                frontier_bytes,
+                block_bytes,
+                hash,
+            ),
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Check every stored block from genesis without modifying anything: hash linkage,
+    /// timestamps, issuance, nullifier uniqueness, value conservation, fees and the stored
+    /// note commitment tree frontiers. Fails with the id of the first invalid block.
+    pub fn verify_chain(&mut self) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let mut statement = tx
+            .prepare(
+                "SELECT id, timestamp, issuance, fee, frontier, block, hash
+                FROM blocks ORDER BY id",
+            )
+            .into_diagnostic()?;
+        let mut rows = statement.query([]).into_diagnostic()?;
+        let mut previous_hash = [0; 32];
+        let mut previous_timestamp = None;
+        let mut nullifiers = HashSet::new();
+        let mut spent_utxos = HashSet::new();
+        let mut frontier: Option<NonEmptyFrontier<MerkleHashOrchard>> = None;
+        let mut expected_id = 1;
+        while let Some(row) = rows.next().into_diagnostic()? {
+            let block_id: u32 = row.get(0).into_diagnostic()?;
+            let timestamp: Option<i64> = row.get(1).into_diagnostic()?;
+            let issuance: u64 = row.get(2).into_diagnostic()?;
+            let fee: u64 = row.get(3).into_diagnostic()?;
+            let frontier_bytes: Option<Vec<u8>> = row.get(4).into_diagnostic()?;
+            let block_bytes: Vec<u8> = row.get(5).into_diagnostic()?;
+            let hash: Option<Vec<u8>> = row.get(6).into_diagnostic()?;
+            let verified: miette::Result<()> = (|| {
+                if block_id != expected_id {
+                    return Err(miette!("expected block {expected_id}"));
+                }
+                let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+                let timestamp = match timestamp {
+                    Some(timestamp) => timestamp,
+                    None => previous_timestamp.unwrap_or(0),
+                };
+                if previous_timestamp.is_some_and(|previous| timestamp < previous) {
+                    return Err(miette!("timestamp is earlier than the previous block's"));
+                }
+                previous_timestamp = Some(timestamp);
+                if let Some(hash) = hash {
+                    let expected = block_hash(&previous_hash, timestamp, issuance, &block_bytes);
+                    if hash != expected {
+                        return Err(miette!("block hash does not match its contents"));
                     }
+                    previous_hash = expected;
                 }
-            }
-            let mut witnesses = vec![];
-            if notes.len() > 0 {
-                let mut frontier = {
-                    let frontier = Self::get_last_frontier(tx)?;
-                    match frontier {
-                        Some(frontier) => frontier,
-                        None => {
-                            let note = notes[0];
-                            notes.remove(0);
-                            let cmx = ExtractedNoteCommitment::from(note.commitment());
-                            let leaf = MerkleHashOrchard::from_cmx(&cmx);
-                            let frontier = NonEmptyFrontier::new(leaf);
-
-                            let witness = {
-                                let frontier: Frontier<MerkleHashOrchard, 32> =
-                                    Frontier::try_from(frontier.clone()).map_err(|_err| {
-                                        miette!("failed to convert NonEmptyFrontier to Frontier")
-                                    })?;
-                                let tree: CommitmentTree<MerkleHashOrchard, 32> =
-                                    CommitmentTree::from_frontier(&frontier);
-                                IncrementalWitness::from_tree(tree)
-                            };
-                            witnesses.push((witness, note));
-                            frontier
+
+                let mut total_fee = 0;
+                for (index, transaction) in block.transactions.iter().enumerate() {
+                    for nullifier in transaction.nullifiers() {
+                        if !nullifiers.insert(nullifier.to_bytes()) {
+                            return Err(miette!("nullifier is revealed twice"));
                         }
                     }
-                };
+                    let bundle_value_balance = transaction
+                        .to_unauthorized_bundle(Anchor::empty_tree())
+                        .map_or(0, |bundle| *bundle.value_balance());
+                    if transaction.value_balance_orchard != bundle_value_balance {
+                        return Err(miette!("value balance does not match the bundle"));
+                    }
+                    Self::verify_authorization(&tx, &self.params, &self.keys, transaction)
+                        .map_err(|err| miette!("transaction {index} is invalid: {err}"))?;
+                    let mut values_in = Vec::new();
+                    for input in &transaction.inputs {
+                        if !spent_utxos.insert(*input) {
+                            return Err(miette!("utxo {input} is spent twice"));
+                        }
+                        let (value, created_block): (i64, Option<u32>) = tx
+                            .query_row(
+                                "SELECT value, created_block FROM utxos WHERE id = ?1",
+                                [input],
+                                |row| Ok((row.get(0)?, row.get(1)?)),
+                            )
+                            .map_err(|_err| miette!("utxo {input} does not exist"))?;
+                        if created_block.is_some_and(|created_block| created_block > block_id) {
+                            return Err(miette!("utxo {input} is spent before it is created"));
+                        }
+                        values_in.push(stored_value(value)?);
+                    }
+                    let value_in = sum_values(values_in)?
+                        .checked_add(transaction.value_balance_orchard)
+                        .ok_or_else(|| miette!("transaction {index} values are out of range"))?;
+                    let value_out =
+                        sum_values(transaction.outputs.iter().map(|output| output.value))?;
+                    if index == 0 && issuance > 0 {
+                        if i128::from(value_out) - i128::from(value_in) != i128::from(issuance) {
+                            return Err(miette!("coinbase does not issue the block issuance"));
+                        }
+                        continue;
+                    }
+                    // Only a negative fee is invalid here. The minimum fee is checked when blocks
+                    // are mined and may have been lower when this one was.
+                    if value_in < value_out {
+                        return Err(miette!("transaction creates value"));
+                    }
+                    let transaction_fee = u64::try_from(value_in - value_out).into_diagnostic()?;
+                    total_fee += transaction_fee;
+                }
+                if total_fee != fee {
+                    return Err(miette!("stored fee {fee} does not match {total_fee}"));
+                }
 
-                for note in notes {
-                    let cmx = ExtractedNoteCommitment::from(note.commitment());
-                    let leaf = MerkleHashOrchard::from_cmx(&cmx);
-                    frontier.append(leaf);
-                    for (witness, _note) in witnesses.iter_mut() {
-                        witness.append(leaf).expect("tree is full");
+                for cmx in &block.extracted_note_commitments() {
+                    let leaf = MerkleHashOrchard::from_cmx(cmx);
+                    match &mut frontier {
+                        Some(frontier) => frontier.append(leaf),
+                        None => frontier = Some(NonEmptyFrontier::new(leaf)),
                     }
-                    let witness = {
-                        let frontier: Frontier<MerkleHashOrchard, 32> =
-                            Frontier::try_from(frontier.clone()).map_err(|_err| {
-                                miette!("failed to convert NonEmptyFrontier to Frontier")
-                            })?;
-                        let tree: CommitmentTree<MerkleHashOrchard, 32> =
-                            CommitmentTree::from_frontier(&frontier);
-                        IncrementalWitness::from_tree(tree)
-                    };
-                    witnesses.push((witness, note));
                 }
+                let stored_root = match frontier_bytes {
+                    Some(frontier_bytes) => {
+                        let stored = deserialize_frontier(&frontier_bytes)?;
+                        Some(stored.root(Some(Level::from(NOTE_COMMITMENT_TREE_DEPTH))))
+                    }
+                    None => None,
+                };
+                let root = frontier
+                    .as_ref()
+                    .map(|frontier| frontier.root(Some(Level::from(NOTE_COMMITMENT_TREE_DEPTH))));
+                if stored_root != root {
+                    return Err(miette!(
+                        "stored frontier does not match the note commitments"
+                    ));
+                }
+                Ok(())
+            })();
+            verified.map_err(|err| miette!("block {block_id} is invalid: {err}"))?;
+            expected_id += 1;
+        }
+        Ok(())
+    }
+
+    pub fn validate_transaction(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        keys: &CircuitKeys,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<u64> {
+        transaction.check_actions()?;
+        Self::verify_authorization(tx, params, keys, transaction)?;
+        let nullifiers = transaction.nullifiers();
+        for nullifier in &nullifiers {
+            if Self::nullifier_exists(tx, nullifier)? {
+                return Err(miette!("nullifier exists, note is already spent"));
             }
+        }
+        let bundle = {
+            let anchor = Self::get_bundle_anchor(tx, params)?;
+            transaction.to_unauthorized_bundle(anchor)
+        };
+        // With actions, the binding signature checked above ties the value balance to the
+        // actions' value commitments. Without actions there is no binding signature and no
+        // Orchard pool to take value from or add it to, so this is the only check keeping the
+        // balance at zero.
+        let bundle_value_balance = bundle.map_or(0, |bundle| *bundle.value_balance());
+        if transaction.value_balance_orchard != bundle_value_balance {
+            return Err(miette!(
+                "value_balance_orchard {} does not match bundle value balance {bundle_value_balance}",
+                transaction.value_balance_orchard
+            ));
+        }
 
-            for (witness, note) in witnesses {
-                Self::store_note(tx, &note, &witness)?;
+        let mut values_in = Vec::new();
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            // A utxo listed twice would have its value counted twice.
+            if transaction.inputs[..index].contains(input) {
+                return Err(miette!("utxo {input} is spent more than once"));
+            }
+            Self::check_utxo_maturity(tx, params, *input)?;
+            let value: i64 = match tx.query_row(
+                "SELECT value FROM utxos
+                WHERE id = ?1 AND spent_block IS NULL AND mempool_tx IS NULL",
+                [input],
+                |row| row.get(0),
+            ) {
+                Ok(value) => value,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    return Err(miette!(
+                        "utxo {input} does not exist, is already spent or is unconfirmed"
+                    ));
+                }
+                Err(err) => return Err(err).into_diagnostic(),
+            };
+            values_in.push(stored_value(value)?);
+        }
+        let value_in = sum_values(values_in)?;
+        let value_out = sum_values(transaction.outputs.iter().map(|output| output.value))?;
+
+        /*
+        A positive Orchard balancing value takes value from the Orchard transaction value pool and
+        adds it to the transparent transaction value pool. A negative Orchard balancing value does
+        the reverse. As a result, positive value_balance_orchard is treated like an input to the
+        transparent transaction value pool, whereas negative value_balance_orchard is treated like
+        an output from that pool.
+
+        The two sources of value don't overlap: value_in only counts distinct transparent utxos,
+        and value_balance_orchard only counts notes revealed by the bundle's nullifiers, so
+
+            value_in + value_balance_orchard = value_out + fee
+
+        with every unit of value entering the transaction exactly once.
+        */
+
+        let value_balance_orchard = transaction.value_balance_orchard;
+
+        let fee = value_in
+            .checked_add(value_balance_orchard)
+            .and_then(|value_in| value_in.checked_sub(value_out))
+            .ok_or_else(|| miette!("transaction values are out of range"))?;
+        let fee = u64::try_from(fee).map_err(|_err| miette!("transaction fee is negative"))?;
+        if fee < params.min_fee {
+            return Err(miette!(
+                "transaction fee {fee} is below the minimum fee {}",
+                params.min_fee
+            ));
+        }
+
+        Ok(fee)
+    }
+
+    /// Transactions with Orchard actions must carry an authorization, whose spend authorization
+    /// and binding signatures are always checked. With proof verification enabled, its proof
+    /// and anchor, a note commitment tree root the chain had at or below the anchor height, are
+    /// checked as well.
+    fn verify_authorization(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        keys: &CircuitKeys,
+        transaction: &crate::types::Transaction,
+    ) -> miette::Result<()> {
+        if transaction.actions.is_empty() {
+            return Ok(());
+        }
+        if transaction.authorization.is_none() {
+            return Err(miette!("Orchard actions are unauthorized"));
+        }
+        let bundle = transaction
+            .to_authorized_bundle()
+            .ok_or_else(|| miette!("Orchard bundle authorization is malformed"))?;
+        Self::verify_signatures(transaction, &bundle)?;
+        if !params.verify_proofs {
+            return Ok(());
+        }
+        if !Self::is_known_anchor(tx, params, bundle.anchor())? {
+            return Err(miette!("Orchard bundle anchor is not a root of the chain"));
+        }
+        bundle
+            .verify_proof(keys.verifying_key())
+            .map_err(|_err| miette!("Orchard proof is invalid"))?;
+        Ok(())
+    }
+
+    /// The binding signature ties the value balance to the actions' value commitments, so a
+    /// tampered `cv_net` or value balance fails here even without checking the proof.
+    fn verify_signatures(
+        transaction: &crate::types::Transaction,
+        bundle: &orchard::Bundle<Authorized, i64>,
+    ) -> miette::Result<()> {
+        let sighash = transaction.txid();
+        for (index, action) in bundle.actions().iter().enumerate() {
+            action
+                .rk()
+                .verify(&sighash, action.authorization())
+                .map_err(|_err| {
+                    miette!("spend authorization signature of action {index} is invalid")
+                })?;
+        }
+        bundle
+            .binding_validating_key()
+            .verify(&sighash, bundle.authorization().binding_signature())
+            .map_err(|_err| miette!("binding signature is invalid"))?;
+        Ok(())
+    }
+
+    fn is_known_anchor(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        anchor: &Anchor,
+    ) -> miette::Result<bool> {
+        if *anchor == Anchor::empty_tree() {
+            return Ok(true);
+        }
+        let Some(anchor_height) = Self::get_anchor_height(tx, params)? else {
+            return Ok(false);
+        };
+        let mut statement = tx
+            .prepare("SELECT frontier FROM blocks WHERE id <= ?1 AND frontier IS NOT NULL")
+            .into_diagnostic()?;
+        let frontiers = statement
+            .query_map([anchor_height], |row| row.get::<_, Vec<u8>>(0))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        for frontier in frontiers {
+            let frontier = deserialize_frontier(&frontier)?;
+            let root = Anchor::from(frontier.root(Some(Level::from(NOTE_COMMITMENT_TREE_DEPTH))));
+            if root == *anchor {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// A block with nonzero `issuance` starts with a coinbase transaction creating exactly that
+    /// much value out of nothing.
+    fn validate_coinbase(
+        tx: &rusqlite::Transaction,
+        transaction: &crate::types::Transaction,
+        issuance: u64,
+    ) -> miette::Result<()> {
+        transaction.check_actions()?;
+        if !transaction.inputs.is_empty() {
+            return Err(miette!("coinbase transaction must not spend utxos"));
+        }
+        for nullifier in &transaction.nullifiers() {
+            if Self::nullifier_exists(tx, nullifier)? {
+                return Err(miette!("nullifier exists, note is already spent"));
+            }
+        }
+        let value_out = sum_values(transaction.outputs.iter().map(|output| output.value))?;
+        // Negative value_balance_orchard moves value into the Orchard pool.
+        let created = i128::from(value_out) - i128::from(transaction.value_balance_orchard);
+        if created != i128::from(issuance) {
+            return Err(miette!(
+                "coinbase transaction creates {created} but the block issues {issuance}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn connect_block(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        keys: &CircuitKeys,
+        block_id: u32,
+        timestamp: i64,
+        issuance: u64,
+        block: &Block,
+    ) -> miette::Result<(Option<NonEmptyFrontier<MerkleHashOrchard>>, u64)> {
+        Self::check_timestamp(tx, timestamp)?;
+
+        // Updating transparent state.
+        let mut total_fee = 0;
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if index == 0 && issuance > 0 {
+                let block_reward = params.block_reward(block_id);
+                if issuance != block_reward {
+                    return Err(miette!(
+                        "block issuance {issuance} does not match the block reward {block_reward}"
+                    ));
+                }
+                Self::validate_coinbase(tx, transaction, issuance)
+                    .and_then(|()| Self::verify_authorization(tx, params, keys, transaction))
+                    .map_err(|err| miette!("transaction {index} is invalid: {err}"))?;
+            } else {
+                let fee = Self::validate_transaction(tx, params, keys, transaction)
+                    .map_err(|err| miette!("transaction {index} is invalid: {err}"))?;
+                total_fee += fee;
+            }
+            for input in &transaction.inputs {
+                tx.execute(
+                    "UPDATE utxos SET spent_block = ?1 WHERE id = ?2",
+                    [block_id, *input],
+                )
+                .into_diagnostic()?;
+            }
+            let pending_utxos = Self::get_pending_utxos(tx, transaction)?;
+            for (index, output) in transaction.outputs.iter().enumerate() {
+                match pending_utxos.get(index) {
+                    Some(utxo_id) => {
+                        tx.execute(
+                            "UPDATE utxos SET created_block = ?1, mempool_tx = NULL WHERE id = ?2",
+                            [block_id, *utxo_id],
+                        )
+                        .into_diagnostic()?;
+                    }
+                    None => {
+                        tx.execute(
+                            "INSERT INTO utxos (value, created_block) VALUES (?1, ?2)",
+                            (output.value, block_id),
+                        )
+                        .into_diagnostic()?;
+                    }
+                }
             }
         }
 
         // Updating Orchard state.
         let frontier = {
-            // TODO: Validate zkSNARK, authorizing signature, binding signature
             let nullifiers = block.nullifiers();
             for nullifier in &nullifiers {
                 // If the same note is spent in the same block this will fail.
-                if Self::nullifier_exists(&tx, nullifier)? {
+                if Self::nullifier_exists(tx, nullifier)? {
                     return Err(miette!("nullifier exists, note is already spent"));
                 }
-                Self::insert_nullifier(&tx, nullifier)?;
-            }
-            let commitments = block.extracted_note_commitments();
-            let last_frontier = Self::get_last_frontier(&tx)?;
-            let frontier: Option<NonEmptyFrontier<MerkleHashOrchard>> = match last_frontier {
-                Some(mut frontier) => {
-                    if !commitments.is_empty() {
-                        for cmx in &commitments {
-                            let leaf = MerkleHashOrchard::from_cmx(cmx);
-                            frontier.append(leaf);
+                Self::insert_nullifier(tx, nullifier, block_id)?;
+                tx.execute(
+                    "UPDATE notes SET spent_block = ?1 WHERE nullifier = ?2",
+                    (block_id, nullifier.to_bytes()),
+                )
+                .into_diagnostic()?;
+            }
+
+            // Storing notes and corresponding merkle proofs. Every commitment in the block is
+            // appended in on-chain order, including the ones that aren't ours, so that each
+            // witness is taken at the exact position of its note. Decrypted notes are looked up
+            // by commitment, so witnesses don't depend on the order decryption returns them in.
+            let notes: HashMap<[u8; 32], (u32, Note, [u8; 512])> =
+                Self::get_notes_with_memos(tx, block)?
+                    .into_iter()
+                    .map(|(account, note, memo)| {
+                        let cmx = ExtractedNoteCommitment::from(note.commitment());
+                        (cmx.to_bytes(), (account, note, memo))
+                    })
+                    .collect();
+            let mut frontier = Self::get_last_frontier(tx)?;
+            let mut witnesses: Vec<(
+                IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>,
+                u32,
+                Note,
+                [u8; 512],
+            )> = vec![];
+            for cmx in &block.extracted_note_commitments() {
+                let leaf = MerkleHashOrchard::from_cmx(cmx);
+                match &mut frontier {
+                    Some(frontier) => {
+                        if u64::from(frontier.position()) + 1 >= 1 << NOTE_COMMITMENT_TREE_DEPTH {
+                            return Err(miette!("note commitment tree is full"));
                         }
+                        frontier.append(leaf);
                     }
-                    Some(frontier)
+                    None => frontier = Some(NonEmptyFrontier::new(leaf)),
                 }
-                None => {
-                    if commitments.is_empty() {
-                        None
-                    } else {
-                        let cmx = &commitments[0];
-                        let leaf = MerkleHashOrchard::from_cmx(cmx);
-                        let mut frontier = NonEmptyFrontier::new(leaf);
-                        for cmx in &commitments[1..] {
-                            let leaf = MerkleHashOrchard::from_cmx(cmx);
-                            frontier.append(leaf);
-                        }
-                        Some(frontier)
-                    }
+                for (witness, _account, _note, _memo) in witnesses.iter_mut() {
+                    witness
+                        .append(leaf)
+                        .map_err(|_err| miette!("note commitment tree is full"))?;
                 }
-            };
+                if let Some((account, note, memo)) = notes.get(&cmx.to_bytes()) {
+                    let frontier = frontier.clone().ok_or_else(|| {
+                        miette!("note commitment tree frontier is missing after appending a leaf")
+                    })?;
+                    let witness = {
+                        let frontier: Frontier<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH> =
+                            Frontier::try_from(frontier).map_err(|_err| {
+                                miette!("failed to convert NonEmptyFrontier to Frontier")
+                            })?;
+                        let tree: CommitmentTree<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH> =
+                            CommitmentTree::from_frontier(&frontier);
+                        IncrementalWitness::from_tree(tree)
+                    };
+                    witnesses.push((witness, *account, *note, *memo));
+                }
+            }
+
+            for (witness, account, note, memo) in witnesses {
+                Self::store_note(tx, account, &note, &witness, Some(block_id), Some(&memo))?;
+            }
             frontier
         };
 
         Ok((frontier, total_fee))
     }
 
-    pub fn mine(&mut self) -> miette::Result<()> {
+    pub fn mine(&mut self) -> miette::Result<MineResult> {
+        self.mine_at(current_timestamp()?, false)
+    }
+
+    /// Mine a block stamped with `timestamp`, in seconds since the unix epoch, out of the
+    /// mempool transactions that are valid. Invalid ones are left in the mempool, since some,
+    /// like spends of immature utxos, may become valid later, or deleted with `drop_rejected`.
+    /// No block is mined if no transaction is valid.
+    pub fn mine_at(&mut self, timestamp: i64, drop_rejected: bool) -> miette::Result<MineResult> {
         let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_frontier_consistency(&tx)?;
+        Self::check_timestamp(&tx, timestamp)?;
         let transactions = Self::get_transactions(&tx)?;
-        if transactions.len() == 0 {
-            return Ok(());
+        let transactions = Self::sort_by_dependencies(&tx, transactions)?;
+        let block_id = Self::get_next_block_id(&tx)?;
+        let TransactionSelection { accepted, rejected } = Self::select_valid_transactions(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            transactions,
+        )?;
+        if drop_rejected {
+            for rejected in &rejected {
+                Self::remove_mempool_transaction(&tx, rejected.mempool_id)?;
+            }
         }
+        let mined: Vec<[u8; 32]> = accepted
+            .iter()
+            .map(|(_id, transaction)| transaction.txid())
+            .collect();
+        let block_id = if accepted.is_empty() {
+            None
+        } else {
+            let mempool_ids: Vec<u32> = accepted.iter().map(|(id, _)| *id).collect();
+            let block = Block {
+                transactions: accepted
+                    .into_iter()
+                    .map(|(_id, transaction)| transaction)
+                    .collect(),
+            };
+            let (frontier, total_fee) = Self::connect_block(
+                &tx,
+                &self.params,
+                &self.keys,
+                block_id,
+                timestamp,
+                0,
+                &block,
+            )?;
+            Self::store_block(&tx, block_id, timestamp, 0, frontier, total_fee, &block)?;
+            for id in mempool_ids {
+                tx.execute("DELETE FROM transactions WHERE id = ?1", [id])
+                    .into_diagnostic()?;
+            }
+            Some(block_id)
+        };
+        tx.commit().into_diagnostic()?;
+        Ok(MineResult {
+            block_id,
+            mined,
+            rejected,
+        })
+    }
+
+    /// Split `transactions`, in dependency order, into the ones that are valid on top of the
+    /// chain and of the ones accepted before them, and the rejected ones. Each transaction is
+    /// connected on its own in a savepoint, and everything is rolled back before returning.
+    fn select_valid_transactions(
+        tx: &rusqlite::Transaction,
+        params: &NetworkParams,
+        keys: &CircuitKeys,
+        block_id: u32,
+        timestamp: i64,
+        transactions: Vec<(u32, crate::types::Transaction)>,
+    ) -> miette::Result<TransactionSelection> {
+        let mut accepted = vec![];
+        let mut rejected = vec![];
+        tx.execute_batch("SAVEPOINT mine_selection")
+            .into_diagnostic()?;
+        for (id, transaction) in transactions {
+            tx.execute_batch("SAVEPOINT mine_candidate")
+                .into_diagnostic()?;
+            let block = Block {
+                transactions: vec![transaction.clone()],
+            };
+            // Validated first for an error that doesn't refer to the transaction's index in
+            // this one transaction block.
+            let connected =
+                Self::validate_transaction(tx, params, keys, &transaction).and_then(|_fee| {
+                    Self::connect_block(tx, params, keys, block_id, timestamp, 0, &block)
+                });
+            match connected {
+                Ok(_) => {
+                    tx.execute_batch("RELEASE mine_candidate")
+                        .into_diagnostic()?;
+                    accepted.push((id, transaction));
+                }
+                Err(err) => {
+                    tx.execute_batch("ROLLBACK TO mine_candidate; RELEASE mine_candidate")
+                        .into_diagnostic()?;
+                    rejected.push(RejectedTransaction {
+                        mempool_id: id,
+                        txid: transaction.txid(),
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+        tx.execute_batch("ROLLBACK TO mine_selection; RELEASE mine_selection")
+            .into_diagnostic()?;
+        Ok(TransactionSelection { accepted, rejected })
+    }
+
+    /// Delete mempool transaction `mempool_id` along with its pending utxos and any staged
+    /// inputs spending them.
+    fn remove_mempool_transaction(
+        tx: &rusqlite::Transaction,
+        mempool_id: u32,
+    ) -> miette::Result<()> {
+        tx.execute(
+            "DELETE FROM inputs
+            WHERE utxo_id IN (SELECT id FROM utxos WHERE mempool_tx = ?1)",
+            [mempool_id],
+        )
+        .into_diagnostic()?;
+        tx.execute("DELETE FROM utxos WHERE mempool_tx = ?1", [mempool_id])
+            .into_diagnostic()?;
+        tx.execute("DELETE FROM transactions WHERE id = ?1", [mempool_id])
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Undo the tip block: its nullifiers are removed, the utxos and notes it spent become
+    /// unspent again and the utxos and notes it created are deleted, along with any staged
+    /// inputs referring to them. The note commitment tree rolls back with it, since the frontier
+    /// is stored per block. The block's transactions are dropped, not returned to the mempool:
+    /// mined utxos don't record the transaction that created them, so resubmitting would give
+    /// them new ids. Mempool transactions spending them are dropped too, see
+    /// `remove_orphaned_mempool_transactions`. Returns the height of the disconnected block.
+    pub fn disconnect_tip(&mut self) -> miette::Result<u32> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let height = Self::get_next_block_id(&tx)? - 1;
+        if height == 0 {
+            return Err(miette!("there is no block to disconnect"));
+        }
+        let block_bytes: Vec<u8> = tx
+            .query_row("SELECT block FROM blocks WHERE id = ?1", [height], |row| {
+                row.get(0)
+            })
+            .into_diagnostic()?;
+        let block: Block = bincode::deserialize(&block_bytes).into_diagnostic()?;
+
+        Self::remove_block_nullifiers(&tx, height)?;
+        // Nullifiers stored before their block was tracked.
+        for nullifier in block.nullifiers() {
+            tx.execute(
+                "DELETE FROM nullifiers WHERE block_id IS NULL AND nullifier = ?1",
+                [nullifier.to_bytes()],
+            )
+            .into_diagnostic()?;
+        }
+        tx.execute(
+            "UPDATE notes SET spent_block = NULL WHERE spent_block = ?1",
+            [height],
+        )
+        .into_diagnostic()?;
+        tx.execute(
+            "DELETE FROM shielded_inputs
+            WHERE note_id IN (SELECT id FROM notes WHERE created_block = ?1)",
+            [height],
+        )
+        .into_diagnostic()?;
+        tx.execute("DELETE FROM notes WHERE created_block = ?1", [height])
+            .into_diagnostic()?;
+
+        tx.execute(
+            "UPDATE utxos SET spent_block = NULL WHERE spent_block = ?1",
+            [height],
+        )
+        .into_diagnostic()?;
+        Self::remove_orphaned_mempool_transactions(&tx, height)?;
+        tx.execute(
+            "DELETE FROM inputs
+            WHERE utxo_id IN (SELECT id FROM utxos WHERE created_block = ?1)",
+            [height],
+        )
+        .into_diagnostic()?;
+        tx.execute("DELETE FROM utxos WHERE created_block = ?1", [height])
+            .into_diagnostic()?;
+
+        tx.execute("DELETE FROM blocks WHERE id = ?1", [height])
+            .into_diagnostic()?;
+        Self::roll_back_witnesses(&tx)?;
+        tx.commit().into_diagnostic()?;
+        Ok(height)
+    }
+
+    /// Delete the mempool transactions spending utxos created in block `height`, and in turn the
+    /// ones spending their pending utxos, since they can't be mined once the block is undone.
+    fn remove_orphaned_mempool_transactions(
+        tx: &rusqlite::Transaction,
+        height: u32,
+    ) -> miette::Result<()> {
+        let mut statement = tx
+            .prepare("SELECT id FROM utxos WHERE created_block = ?1")
+            .into_diagnostic()?;
+        let mut removed_utxos: HashSet<u32> = statement
+            .query_map([height], |row| row.get(0))
+            .into_diagnostic()?
+            .collect::<Result<_, _>>()
+            .into_diagnostic()?;
+        let mut mempool = Self::get_transactions(tx)?;
+        while let Some(index) = mempool.iter().position(|(_id, transaction)| {
+            transaction
+                .inputs
+                .iter()
+                .any(|input| removed_utxos.contains(input))
+        }) {
+            let (mempool_id, _transaction) = mempool.remove(index);
+            let mut statement = tx
+                .prepare("SELECT id FROM utxos WHERE mempool_tx = ?1")
+                .into_diagnostic()?;
+            let pending_utxos = statement
+                .query_map([mempool_id], |row| row.get::<_, u32>(0))
+                .into_diagnostic()?
+                .collect::<Result<Vec<_>, _>>()
+                .into_diagnostic()?;
+            removed_utxos.extend(pending_utxos);
+            Self::remove_mempool_transaction(tx, mempool_id)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the stored witnesses that cover note commitments past the tip, like the ones
+    /// advanced by `rebuild_witness` or imported from another wallet, by replaying the chain that
+    /// remains. Notes that are no longer in the chain at all are deleted, along with staged spends
+    /// of them.
+    fn roll_back_witnesses(tx: &rusqlite::Transaction) -> miette::Result<()> {
+        let tree_size = Self::get_tree_size(tx)?;
+        let tip = Self::get_next_block_id(tx)? - 1;
+        let mut statement = tx
+            .prepare("SELECT id, witness FROM notes")
+            .into_diagnostic()?;
+        let witnesses: Vec<(u32, Vec<u8>)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        for (note_id, witness) in witnesses {
+            let witness = deserialize_incremental_witness(&witness)
+                .map_err(|err| miette!("note {note_id} has a malformed witness: {err}"))?;
+            if u64::from(witness.tip_position()) < tree_size {
+                continue;
+            }
+            let note = Self::get_note(tx, note_id)?;
+            match Self::replay_witness(tx, &note, tip)? {
+                Some(witness) => {
+                    tx.execute(
+                        "UPDATE notes SET witness = ?1 WHERE id = ?2",
+                        (serialize_incremental_witness(&witness)?, note_id),
+                    )
+                    .into_diagnostic()?;
+                }
+                None => {
+                    tx.execute("DELETE FROM shielded_inputs WHERE note_id = ?1", [note_id])
+                        .into_diagnostic()?;
+                    tx.execute("DELETE FROM notes WHERE id = ?1", [note_id])
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Assemble and validate the block that `mine_at(timestamp)` would produce without storing
+    /// it. `None` if the mempool has no valid transactions.
+    pub fn peek_next_block(&mut self, timestamp: i64) -> miette::Result<Option<BlockPreview>> {
+        // Connect and store the block as mine_at does, then roll back by not committing.
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_timestamp(&tx, timestamp)?;
+        let transactions = Self::get_transactions(&tx)?;
+        let transactions = Self::sort_by_dependencies(&tx, transactions)?;
+        let block_id = Self::get_next_block_id(&tx)?;
+        let TransactionSelection { accepted, .. } = Self::select_valid_transactions(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            transactions,
+        )?;
+        if accepted.is_empty() {
+            return Ok(None);
+        }
+        let block = Block {
+            transactions: accepted
+                .into_iter()
+                .map(|(_id, transaction)| transaction)
+                .collect(),
+        };
+        let (frontier, fee) = Self::connect_block(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            0,
+            &block,
+        )?;
+        Self::store_block(&tx, block_id, timestamp, 0, frontier, fee, &block)?;
+        let hash = Self::get_block_hash(&tx, block_id)?;
+        Ok(Some(BlockPreview {
+            height: block_id,
+            timestamp,
+            hash,
+            fee,
+            block,
+        }))
+    }
+
+    /// Mine `blocks` blocks of `txns_per_block` transactions, each shielding a conjured utxo
+    /// into a note to this wallet, and time the whole workload. This writes to the database
+    /// like any other use of the wallet, so run it on a scratch database.
+    pub fn bench_mine(&mut self, blocks: u32, txns_per_block: u32) -> miette::Result<BenchReport> {
+        let start = std::time::Instant::now();
+        for _ in 0..blocks {
+            for _ in 0..txns_per_block {
+                self.conjure_utxo(1000)?;
+                let utxo_id = self.conn.last_insert_rowid() as u32;
+                let recipient = self.get_new_address(0)?;
+                let transaction = {
+                    let tx = self.conn.transaction().into_diagnostic()?;
+                    let mut builder = TransactionBuilder::new(&tx, &self.params)?;
+                    builder.add_transparent_input(utxo_id);
+                    builder.add_shielded_output(recipient, 900, None)?;
+                    builder.build(&self.keys)?
+                };
+                self.submit_built(&transaction)?;
+            }
+            self.mine()?;
+        }
+        Ok(BenchReport {
+            blocks,
+            notes: blocks * txns_per_block,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Mine a block containing exactly the mempool transactions with the given txids, leaving
+    /// the rest of the mempool pending.
+    pub fn mine_selected(&mut self, txids: &[[u8; 32]]) -> miette::Result<()> {
+        let timestamp = current_timestamp()?;
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_frontier_consistency(&tx)?;
+        let mempool = Self::get_transactions(&tx)?;
+        for txid in txids {
+            if !mempool
+                .iter()
+                .any(|(_id, transaction)| transaction.txid() == *txid)
+            {
+                return Err(miette!(
+                    "transaction {} is not in the mempool",
+                    hex::encode(txid)
+                ));
+            }
+        }
+        let selected: Vec<(u32, crate::types::Transaction)> = mempool
+            .into_iter()
+            .filter(|(_id, transaction)| txids.contains(&transaction.txid()))
+            .collect();
+        let mempool_ids: Vec<u32> = selected.iter().map(|(id, _)| *id).collect();
+        let transactions = Self::sort_by_dependencies(&tx, selected)?
+            .into_iter()
+            .map(|(_id, transaction)| transaction)
+            .collect();
         let block = Block { transactions };
-        let (frontier, total_fee) = Self::connect_block(&tx, &block)?;
-        Self::store_block(&tx, frontier, total_fee, &block)?;
-        Self::clear_transactions(&tx)?;
+        let block_id = Self::get_next_block_id(&tx)?;
+        let (frontier, total_fee) = Self::connect_block(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            0,
+            &block,
+        )?;
+        Self::store_block(&tx, block_id, timestamp, 0, frontier, total_fee, &block)?;
+        for id in mempool_ids {
+            tx.execute("DELETE FROM transactions WHERE id = ?1", [id])
+                .into_diagnostic()?;
+        }
         tx.commit().into_diagnostic()?;
         Ok(())
     }
 
+    /// Mine a block whose only transaction is a coinbase issuing the block reward, as a
+    /// transparent utxo or, if `recipient` is given, as a note to that shielded address. Unlike
+    /// conjured utxos, issuance is recorded on the block and can be audited.
+    pub fn mine_coinbase(&mut self, recipient: Option<String>) -> miette::Result<()> {
+        let timestamp = current_timestamp()?;
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let block_id = Self::get_next_block_id(&tx)?;
+        let value = self.params.block_reward(block_id);
+        let recipient = match recipient {
+            Some(recipient) => Some(recipient),
+            None => Self::get_config(&tx, MINER_ADDRESS_KEY)?,
+        };
+        let transaction = match recipient {
+            Some(recipient) => {
+                let recipient = decode_shielded_address(&self.params, &recipient)?;
+                let mut builder = TransactionBuilder::new(&tx, &self.params)?;
+                builder.add_shielded_output(recipient, value, None)?;
+                builder.build(&self.keys)?
+            }
+            None => crate::types::Transaction {
+                inputs: vec![],
+                outputs: vec![Output { value }],
+                actions: vec![],
+                value_balance_orchard: 0,
+                authorization: None,
+            },
+        };
+        let block = Block {
+            transactions: vec![transaction],
+        };
+        let (frontier, total_fee) = Self::connect_block(
+            &tx,
+            &self.params,
+            &self.keys,
+            block_id,
+            timestamp,
+            value,
+            &block,
+        )?;
+        Self::store_block(&tx, block_id, timestamp, value, frontier, total_fee, &block)?;
+        tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Pay coinbase rewards mined without an explicit recipient to `address`, or to a
+    /// transparent utxo again if `address` is `None`.
+    pub fn set_miner_address(&mut self, address: Option<&str>) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        match address {
+            Some(address) => {
+                decode_shielded_address(&self.params, address)?;
+                tx.execute(
+                    "INSERT INTO config (key, value) VALUES (?1, ?2)
+                    ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    (MINER_ADDRESS_KEY, address),
+                )
+                .into_diagnostic()?;
+            }
+            None => {
+                tx.execute("DELETE FROM config WHERE key = ?1", [MINER_ADDRESS_KEY])
+                    .into_diagnostic()?;
+            }
+        }
+        tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    pub fn get_miner_address(&mut self) -> miette::Result<Option<String>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::get_config(&tx, MINER_ADDRESS_KEY)
+    }
+
+    fn get_config(tx: &rusqlite::Transaction, key: &str) -> miette::Result<Option<String>> {
+        match tx.query_row("SELECT value FROM config WHERE key = ?1", [key], |row| {
+            row.get(0)
+        }) {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err).into_diagnostic(),
+        }
+    }
+
+    /// Account for all value in the system: everything issued by coinbases or conjured must
+    /// be in the transparent pool, the Orchard pool or paid as fees.
+    pub fn supply_audit(&mut self) -> miette::Result<SupplyAudit> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let (issued, fees): (u64, u64) = tx
+            .query_row(
+                "SELECT COALESCE(SUM(issuance), 0), COALESCE(SUM(fee), 0) FROM blocks",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .into_diagnostic()?;
+        let conjured: u64 = tx
+            .query_row(
+                "SELECT COALESCE(SUM(value), 0) FROM utxos
+                WHERE created_block IS NULL AND mempool_tx IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        let transparent: u64 = tx
+            .query_row(
+                "SELECT COALESCE(SUM(value), 0) FROM utxos
+                WHERE spent_block IS NULL AND mempool_tx IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        let mut shielded: i64 = 0;
+        Self::for_each_block(&tx, |_block_id, block| {
+            for transaction in &block.transactions {
+                shielded -= transaction.value_balance_orchard;
+            }
+            Ok(())
+        })?;
+        Ok(SupplyAudit {
+            issued,
+            conjured,
+            fees,
+            transparent,
+            shielded: u64::try_from(shielded)
+                .map_err(|_err| miette!("the shielded pool holds a negative value {shielded}"))?,
+        })
+    }
+
+    /// Value conservation issues found by the supply audit, empty if there are none. Value
+    /// created by coinbases and conjuring must all be accounted for by the pools and fees, and
+    /// since no output collects fees yet, any fee paid is reported as value leaving the system.
+    pub fn check_integrity(&mut self) -> miette::Result<Vec<String>> {
+        let audit = self.supply_audit()?;
+        let mut issues = vec![];
+        if !audit.is_balanced() {
+            issues.push(format!(
+                "supply doesn't balance: {} issued and {} conjured, but {} transparent, {} shielded and {} fees",
+                Amount(audit.issued),
+                Amount(audit.conjured),
+                Amount(audit.transparent),
+                Amount(audit.shielded),
+                Amount(audit.fees)
+            ));
+        }
+        if audit.fees > 0 {
+            issues.push(format!(
+                "{} paid as fees has left the system, fees aren't credited to miners yet",
+                Amount(audit.fees)
+            ));
+        }
+        Ok(issues)
+    }
+
+    pub fn status(&mut self) -> miette::Result<Status> {
+        let transparent_value = self.get_total_transparent_value()?;
+        let shielded_value = self.get_total_shielded_value(None)?;
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let count = |sql: &str| -> miette::Result<u32> {
+            tx.query_row(sql, [], |row| row.get(0)).into_diagnostic()
+        };
+        let schema_version = count("PRAGMA user_version")?;
+        let mempool_size = count("SELECT COUNT(*) FROM transactions")?;
+        let nullifier_count = count("SELECT COUNT(*) FROM nullifiers")?;
+        let height = Self::get_next_block_id(&tx)? - 1;
+        let tree_size = Self::get_tree_size(&tx)?;
+        Ok(Status {
+            schema_version,
+            height,
+            mempool_size,
+            transparent_value,
+            shielded_value,
+            nullifier_count,
+            tree_size,
+        })
+    }
+
     fn generate_seed(tx: &rusqlite::Transaction) -> miette::Result<()> {
         let mnemonic = Mnemonic::new(bip39::MnemonicType::Words12, bip39::Language::English);
         let phrase = mnemonic.phrase().to_string();
@@ -667,6 +2865,131 @@ impl Db {
         Ok(())
     }
 
+    /// Replace the wallet seed with `phrase`, for restoring a wallet from a backup. Generated
+    /// addresses of the old seed are cleared so they get re-derived. A wallet that already has
+    /// notes is only overwritten with `force`, which drops the notes; notes of the restored seed
+    /// can then be recovered with find-missing and import-note.
+    pub fn restore_from_mnemonic(&mut self, phrase: &str, force: bool) -> miette::Result<()> {
+        let mnemonic = Mnemonic::from_phrase(phrase, bip39::Language::English)
+            .map_err(|err| miette!("invalid mnemonic phrase: {err}"))?;
+        let tx = self.conn.transaction().into_diagnostic()?;
+        if Self::is_seed_encrypted(&tx)? {
+            return Err(miette!(
+                "wallet seed is encrypted, restore into a new database instead"
+            ));
+        }
+        let note_count: u32 = tx
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))
+            .into_diagnostic()?;
+        if note_count > 0 {
+            if !force {
+                return Err(miette!(
+                    "wallet already has {note_count} notes, use --force to restore anyway and drop them"
+                ));
+            }
+            tx.execute("DELETE FROM shielded_inputs", [])
+                .into_diagnostic()?;
+            tx.execute("DELETE FROM notes", []).into_diagnostic()?;
+        }
+        tx.execute("DELETE FROM wallet_seed", [])
+            .into_diagnostic()?;
+        tx.execute(
+            "INSERT INTO wallet_seed (phrase) VALUES (?1)",
+            [mnemonic.phrase()],
+        )
+        .into_diagnostic()?;
+        tx.execute("DELETE FROM addresses", []).into_diagnostic()?;
+        tx.execute("DELETE FROM change_addresses", [])
+            .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    fn is_seed_encrypted(tx: &rusqlite::Transaction) -> miette::Result<bool> {
+        let encrypted = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM encrypted_wallet_seed)",
+                [],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        Ok(encrypted)
+    }
+
+    pub fn encrypt_wallet(&mut self, password: &str) -> miette::Result<()> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        if Self::is_seed_encrypted(&tx)? {
+            return Err(miette!("wallet seed is already encrypted"));
+        }
+        Self::encrypt_seed(&tx, password)?;
+        tx.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    fn encrypt_seed(tx: &rusqlite::Transaction, password: &str) -> miette::Result<()> {
+        let mnemonic = Self::get_mnemonic(tx)?;
+        let phrase = mnemonic.phrase();
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+        let cipher = seed_cipher(password, &salt);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), phrase.as_bytes())
+            .map_err(|_err| miette!("failed to encrypt wallet seed"))?;
+        tx.execute(
+            "INSERT INTO encrypted_wallet_seed (ciphertext, salt, nonce) VALUES (?1, ?2, ?3)",
+            (ciphertext, salt, nonce),
+        )
+        .into_diagnostic()?;
+        // Overwrite the plaintext phrase instead of just unlinking it.
+        tx.pragma_update(None, "secure_delete", true)
+            .into_diagnostic()?;
+        tx.execute("DELETE FROM main.wallet_seed", [])
+            .into_diagnostic()?;
+        Self::cache_unlocked_seed(tx, phrase)?;
+        Ok(())
+    }
+
+    fn unlock_seed(tx: &rusqlite::Transaction, password: &str) -> miette::Result<()> {
+        let (ciphertext, salt, nonce): (Vec<u8>, Vec<u8>, Vec<u8>) = tx
+            .query_row(
+                "SELECT ciphertext, salt, nonce FROM encrypted_wallet_seed",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .into_diagnostic()?;
+        let nonce: [u8; 12] = nonce
+            .try_into()
+            .map_err(|_err| miette!("wrong wallet seed nonce length"))?;
+        let cipher = seed_cipher(password, &salt);
+        let phrase = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_err| miette!("wrong wallet password"))?;
+        let phrase = String::from_utf8(phrase).into_diagnostic()?;
+        Self::cache_unlocked_seed(tx, &phrase)?;
+        Ok(())
+    }
+
+    /// Unqualified table names resolve to the temp schema first, so a temporary wallet_seed
+    /// table holding the decrypted phrase shadows the empty main.wallet_seed for the lifetime
+    /// of this connection, and get_mnemonic works the same for encrypted wallets.
+    fn cache_unlocked_seed(tx: &rusqlite::Transaction, phrase: &str) -> miette::Result<()> {
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS wallet_seed(
+                phrase TEXT NOT NULL
+            );",
+            [],
+        )
+        .into_diagnostic()?;
+        tx.execute("DELETE FROM temp.wallet_seed", [])
+            .into_diagnostic()?;
+        tx.execute(
+            "INSERT INTO temp.wallet_seed (phrase) VALUES (?1)",
+            [phrase],
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+
     pub fn get_mnemonic(tx: &rusqlite::Transaction) -> miette::Result<Mnemonic> {
         let phrase: String = tx
             .query_row("SELECT phrase FROM wallet_seed", [], |row| row.get(0))
@@ -676,35 +2999,247 @@ impl Db {
         Ok(mnemonic)
     }
 
-    pub fn get_sk(tx: &rusqlite::Transaction) -> miette::Result<orchard::keys::SpendingKey> {
+    pub fn get_sk(
+        tx: &rusqlite::Transaction,
+        account: u32,
+    ) -> miette::Result<orchard::keys::SpendingKey> {
+        let account_id = AccountId::try_from(account)
+            .map_err(|_err| miette!("account {account} is out of range"))?;
         let mnemonic = Self::get_mnemonic(tx)?;
         let seed = Seed::new(&mnemonic, "");
         let seed_bytes = seed.as_bytes();
-        let sk = orchard::keys::SpendingKey::from_zip32_seed(seed_bytes, 0, AccountId::ZERO)
-            .expect("couldn't derive spending key from seed");
+        let sk = orchard::keys::SpendingKey::from_zip32_seed(seed_bytes, 0, account_id)
+            .map_err(|_err| miette!("couldn't derive spending key of account {account}"))?;
         Ok(sk)
     }
 
-    pub fn get_new_address(&mut self) -> miette::Result<Address> {
+    /// Ids of the wallet accounts in ascending order. Account 0 always exists.
+    pub fn get_accounts(tx: &rusqlite::Transaction) -> miette::Result<Vec<u32>> {
+        let mut statement = tx
+            .prepare("SELECT id FROM accounts ORDER BY id")
+            .into_diagnostic()?;
+        let accounts = statement
+            .query_map([], |row| row.get(0))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        Ok(accounts)
+    }
+
+    fn check_account(tx: &rusqlite::Transaction, account: u32) -> miette::Result<()> {
+        let exists: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM accounts WHERE id = ?1)",
+                [account],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        if !exists {
+            return Err(miette!(
+                "account {account} does not exist, create it with new-account"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add the next ZIP 32 account of the wallet seed, returning its id.
+    pub fn new_account(&mut self) -> miette::Result<u32> {
         let tx = self.conn.transaction().into_diagnostic()?;
-        let sk = Self::get_sk(&tx)?;
+        let account: u32 = tx
+            .query_row("SELECT MAX(id) + 1 FROM accounts", [], |row| row.get(0))
+            .into_diagnostic()?;
+        // Derive the key first so that an account that can't be derived isn't stored.
+        Self::get_sk(&tx, account)?;
+        tx.execute("INSERT INTO accounts (id) VALUES (?1)", [account])
+            .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+        Ok(account)
+    }
 
-        let index: u32 = match tx.query_row(
-            "SELECT id FROM addresses ORDER BY id DESC LIMIT 1",
-            [],
+    pub fn get_note_account(tx: &rusqlite::Transaction, note_id: u32) -> miette::Result<u32> {
+        match tx.query_row(
+            "SELECT account FROM notes WHERE id = ?1",
+            [note_id],
             |row| row.get(0),
         ) {
-            Ok(index) => index,
-            Err(rusqlite::Error::QueryReturnedNoRows) => 0,
-            Err(err) => return Err(err).into_diagnostic(),
-        };
+            Ok(account) => Ok(account),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                Err(miette!("note {note_id} does not exist"))
+            }
+            Err(err) => Err(err).into_diagnostic(),
+        }
+    }
+
+    /// External and internal incoming viewing keys of every account, with the account they
+    /// belong to.
+    fn get_wallet_ivks(
+        tx: &rusqlite::Transaction,
+    ) -> miette::Result<Vec<(u32, orchard::keys::IncomingViewingKey)>> {
+        let mut ivks = vec![];
+        for account in Self::get_accounts(tx)? {
+            let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(tx, account)?);
+            ivks.push((account, fvk.to_ivk(zip32::Scope::External)));
+            ivks.push((account, fvk.to_ivk(zip32::Scope::Internal)));
+        }
+        Ok(ivks)
+    }
+
+    /// Short identifier of the wallet derived from the full viewing key of account 0, so two
+    /// setups can be checked to share a wallet without revealing any keys.
+    pub fn wallet_fingerprint(&mut self) -> miette::Result<String> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let sk = Self::get_sk(&tx, 0)?;
+        let fvk = orchard::keys::FullViewingKey::from(&sk);
+        let hash = blake3::hash(&fvk.to_bytes());
+        Ok(hex::encode(&hash.as_bytes()[..8]))
+    }
+
+    /// Diversifier indexes are counted per account. The address is derived before the only
+    /// write and the transaction is rolled back when dropped, so a failed call leaves no row
+    /// behind and doesn't skip an index.
+    pub fn get_new_address(&mut self, account: u32) -> miette::Result<Address> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_account(&tx, account)?;
+        let sk = Self::get_sk(&tx, account)?;
+
+        let index: u32 = tx
+            .query_row(
+                "SELECT COALESCE(MAX(diversifier_index), 0) FROM addresses WHERE account = ?1",
+                [account],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
 
         let fvk = orchard::keys::FullViewingKey::from(&sk);
         let address = fvk.address_at(index + 1, zip32::Scope::External);
 
         tx.execute(
-            "INSERT INTO addresses (address) VALUES (?)",
-            [address.to_raw_address_bytes()],
+            "INSERT INTO addresses (address, account, diversifier_index) VALUES (?1, ?2, ?3)",
+            (address.to_raw_address_bytes(), account, index + 1),
+        )
+        .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+
+        Ok(address)
+    }
+
+    /// External address of `account` generated at diversifier `index` by `get_new_address`, if
+    /// any.
+    pub fn get_address_at(
+        tx: &rusqlite::Transaction,
+        account: u32,
+        index: u32,
+    ) -> miette::Result<Option<Address>> {
+        match tx.query_row(
+            "SELECT address FROM addresses WHERE account = ?1 AND diversifier_index = ?2",
+            [account, index],
+            |row| row.get(0),
+        ) {
+            Ok(address) => Ok(Some(address_from_bytes(address)?)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err).into_diagnostic(),
+        }
+    }
+
+    /// External addresses generated for `account` with their diversifier indexes, in the order
+    /// they were generated.
+    pub fn list_addresses(
+        tx: &rusqlite::Transaction,
+        account: u32,
+    ) -> miette::Result<Vec<(u32, Address)>> {
+        let mut statement = tx
+            .prepare(
+                "SELECT diversifier_index, address FROM addresses
+                WHERE account = ?1 ORDER BY diversifier_index",
+            )
+            .into_diagnostic()?;
+        let rows = statement
+            .query_map([account], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        rows.into_iter()
+            .map(|(index, address)| Ok((index, address_from_bytes(address)?)))
+            .collect()
+    }
+
+    /// Re-derive every stored external and change address from the current seed at its account
+    /// and diversifier index, returning how many were checked. Fails listing the addresses that
+    /// don't match, for instance after the seed was replaced.
+    pub fn verify_addresses(&mut self) -> miette::Result<u32> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let mut fvks = HashMap::new();
+        for account in Self::get_accounts(&tx)? {
+            let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(&tx, account)?);
+            fvks.insert(account, fvk);
+        }
+        let mut checked = 0;
+        let mut mismatches = vec![];
+        for (table, kind, scope) in [
+            ("addresses", "address", zip32::Scope::External),
+            ("change_addresses", "change address", zip32::Scope::Internal),
+        ] {
+            let mut statement = tx
+                .prepare(&format!(
+                    "SELECT account, diversifier_index, address FROM {table}
+                    ORDER BY account, diversifier_index"
+                ))
+                .into_diagnostic()?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                    ))
+                })
+                .into_diagnostic()?
+                .collect::<Result<Vec<_>, _>>()
+                .into_diagnostic()?;
+            for (account, index, address) in rows {
+                let derived = fvks
+                    .get(&account)
+                    .map(|fvk| fvk.address_at(index, scope).to_raw_address_bytes());
+                if derived.as_ref().map(|derived| &derived[..]) != Some(&address[..]) {
+                    mismatches.push(format!("{kind} {index} of account {account}"));
+                }
+                checked += 1;
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(miette!(
+                "stored addresses don't match the wallet seed: {}",
+                mismatches.join(", ")
+            ));
+        }
+        Ok(checked)
+    }
+
+    /// Change goes to internal scope addresses, with a new diversifier for every change note so
+    /// that change outputs can't be linked to each other.
+    pub fn next_change_address(&mut self, account: u32) -> miette::Result<Address> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_account(&tx, account)?;
+        let sk = Self::get_sk(&tx, account)?;
+
+        let index: u32 = tx
+            .query_row(
+                "SELECT COALESCE(MAX(diversifier_index), 0) FROM change_addresses
+                WHERE account = ?1",
+                [account],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+
+        let fvk = orchard::keys::FullViewingKey::from(&sk);
+        let address = fvk.address_at(index + 1, zip32::Scope::Internal);
+
+        tx.execute(
+            "INSERT INTO change_addresses (address, account, diversifier_index)
+            VALUES (?1, ?2, ?3)",
+            (address.to_raw_address_bytes(), account, index + 1),
         )
         .into_diagnostic()?;
         tx.commit().into_diagnostic()?;
@@ -713,48 +3248,119 @@ impl Db {
     }
 
     pub fn get_total_transparent_value(&self) -> miette::Result<u64> {
-        let total_value: u64 =
-            match self
-                .conn
-                .query_row("SELECT SUM(value) FROM utxos", [], |row| row.get(0))
-            {
-                Ok(total_value) => total_value,
-                Err(rusqlite::Error::InvalidColumnType(..)) => 0,
-                Err(err) => return Err(err).into_diagnostic(),
-            };
+        let total_value: u64 = match self.conn.query_row(
+            "SELECT SUM(value) FROM utxos WHERE spent_block IS NULL AND mempool_tx IS NULL",
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(total_value) => total_value,
+            Err(rusqlite::Error::InvalidColumnType(..)) => 0,
+            Err(err) => return Err(err).into_diagnostic(),
+        };
         Ok(total_value)
     }
 
-    pub fn get_total_shielded_value(&self) -> miette::Result<u64> {
-        let total_value: u64 =
-            match self
-                .conn
-                .query_row("SELECT SUM(value) FROM notes", [], |row| row.get(0))
-            {
-                Ok(total_value) => total_value,
-                Err(rusqlite::Error::InvalidColumnType(..)) => 0,
-                Err(err) => return Err(err).into_diagnostic(),
-            };
+    /// Value of unspent notes of `account`, or of every account when `None`.
+    pub fn get_total_shielded_value(&self, account: Option<u32>) -> miette::Result<u64> {
+        let total_value: u64 = match self.conn.query_row(
+            "SELECT SUM(value) FROM notes
+            WHERE spent_block IS NULL AND (?1 IS NULL OR account = ?1)",
+            [account],
+            |row| row.get(0),
+        ) {
+            Ok(total_value) => total_value,
+            Err(rusqlite::Error::InvalidColumnType(..)) => 0,
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        Ok(total_value)
+    }
+
+    /// Value of unspent notes that can be spent right now: deep enough to be in the bundle
+    /// anchor and not already staged as a shielded input. Notes with an unknown creation block,
+    /// such as imported ones, are assumed to be deep enough. Restricted to `account` if given.
+    pub fn get_spendable_shielded_value(&mut self, account: Option<u32>) -> miette::Result<u64> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let tip = Self::get_next_block_id(&tx)? - 1;
+        let total_value: u64 = tx
+            .query_row(
+                "SELECT COALESCE(SUM(value), 0) FROM notes
+                WHERE spent_block IS NULL
+                AND (created_block IS NULL OR created_block + ?1 <= ?2)
+                AND id NOT IN (SELECT note_id FROM shielded_inputs)
+                AND (?3 IS NULL OR account = ?3)",
+                (self.params.anchor_depth, tip, account),
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
         Ok(total_value)
     }
 
+    /// Transparent and shielded value of this wallet as of block `block_id`: utxos and notes
+    /// created at or below it and not spent at or below it. Conjured utxos count above the tip
+    /// height they were conjured at. Imported notes, and utxos conjured before that height was
+    /// recorded, count at every height. Notes deleted by `prune_spent_notes` no longer count
+    /// towards the heights they were unspent at.
+    pub fn balance_at(&mut self, block_id: u32) -> miette::Result<(u64, u64)> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let tip = Self::get_next_block_id(&tx)? - 1;
+        if block_id > tip {
+            return Err(miette!("block {block_id} is above the tip at height {tip}"));
+        }
+        let transparent: u64 = tx
+            .query_row(
+                "SELECT COALESCE(SUM(value), 0) FROM utxos
+                WHERE mempool_tx IS NULL
+                AND (created_block IS NULL OR created_block <= ?1)
+                AND (conjured_height IS NULL OR conjured_height < ?1)
+                AND (spent_block IS NULL OR spent_block > ?1)",
+                [block_id],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        let shielded: u64 = tx
+            .query_row(
+                "SELECT COALESCE(SUM(value), 0) FROM notes
+                WHERE (created_block IS NULL OR created_block <= ?1)
+                AND (spent_block IS NULL OR spent_block > ?1)",
+                [block_id],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        Ok((transparent, shielded))
+    }
+
+    /// Conjured utxos have no creation block, the tip height is recorded instead so that
+    /// `balance_at` doesn't count them at earlier heights.
     pub fn conjure_utxo(&self, value: u64) -> miette::Result<()> {
+        if !self.params.allow_conjure {
+            return Err(miette!(
+                "conjuring utxos is disabled on this network, new coins can only be issued by coinbases"
+            ));
+        }
         self.conn
-            .execute("INSERT INTO utxos (value) VALUES (?1)", [value])
+            .execute(
+                "INSERT INTO utxos (value, conjured_height)
+                VALUES (?1, (SELECT COALESCE(MAX(id), 0) FROM blocks))",
+                [value],
+            )
             .into_diagnostic()?;
         Ok(())
     }
 
-    pub fn get_utxos(&self) -> miette::Result<Vec<(u32, u64)>> {
+    /// Unspent utxos with their values and whether they are locked.
+    pub fn get_utxos(&self) -> miette::Result<Vec<(u32, u64, bool)>> {
         let mut statement = self
             .conn
-            .prepare("SELECT id, value FROM utxos")
+            .prepare(
+                "SELECT id, value, locked FROM utxos WHERE spent_block IS NULL AND mempool_tx IS NULL",
+            )
             .into_diagnostic()?;
-        let utxos: Vec<(u32, u64)> = statement
+        let utxos: Vec<(u32, u64, bool)> = statement
             .query_map([], |row| {
                 let id = row.get(0)?;
                 let value = row.get(1)?;
-                Ok((id, value))
+                let locked = row.get(2)?;
+                Ok((id, value, locked))
             })
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
@@ -762,85 +3368,278 @@ impl Db {
         Ok(utxos)
     }
 
-    pub fn get_wallet_notes(
-        &self,
-    ) -> miette::Result<Vec<(u32, Note, IncrementalWitness<MerkleHashOrchard, 32>)>> {
+    /// Wallet notes with their witnesses and memos.
+    pub fn get_wallet_notes(&self) -> miette::Result<Vec<WalletNote>> {
         let mut statement = self
             .conn
-            .prepare("SELECT id, recipient, value, rho, rseed, witness FROM notes")
+            .prepare("SELECT id, recipient, value, rho, rseed, witness, memo FROM notes")
             .into_diagnostic()?;
-        let notes: Vec<(u32, Note, IncrementalWitness<MerkleHashOrchard, 32>)> = statement
+        let notes = statement
             .query_map([], |row| {
-                let id = row.get(0)?;
-                let note = {
-                    let recipient: Vec<u8> = row.get(1)?;
-                    let recipient: [u8; 43] =
-                        recipient.try_into().expect("wrong shielded address length");
-                    let recipient = Address::from_raw_address_bytes(&recipient)
-                        .expect("subtle error, failed to convert bytes to shielded address");
-                    let value = row.get(2)?;
-                    let value = NoteValue::from_raw(value);
-                    let rho: Vec<u8> = row.get(3)?;
-                    let rho: [u8; 32] = rho.try_into().expect("wrong rho length");
-                    let rho = Rho::from_bytes(&rho)
-                        .expect("subtle error, failed to convert bytes to rho");
-                    let rseed: Vec<u8> = row.get(4)?;
-                    let rseed: [u8; 32] = rseed.try_into().expect("wrong rseed length");
-                    let rseed = RandomSeed::from_bytes(rseed, &rho)
-                        .expect("subtle error, failed to convert bytes to rseed");
-                    Note::from_parts(recipient, value, rho, rseed)
-                        .expect("subtle error, failed to construct note")
-                };
+                let id: u32 = row.get(0)?;
+                let recipient: Vec<u8> = row.get(1)?;
+                let value: u64 = row.get(2)?;
+                let rho: Vec<u8> = row.get(3)?;
+                let rseed: Vec<u8> = row.get(4)?;
                 let witness: Vec<u8> = row.get(5)?;
+                let memo: Option<Vec<u8>> = row.get(6)?;
+                Ok((id, recipient, value, rho, rseed, witness, memo))
+            })
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        notes
+            .into_iter()
+            .map(|(id, recipient, value, rho, rseed, witness, memo)| {
+                let note = note_from_parts(id, recipient, value, rho, rseed)?;
                 let witness = deserialize_incremental_witness(&witness)
-                    .expect("failed to deserialize incremental witness");
-                Ok((id, note, witness))
+                    .map_err(|err| miette!("note {id} has a malformed witness: {err}"))?;
+                let memo: Option<[u8; 512]> = memo
+                    .map(|memo| {
+                        memo.try_into()
+                            .map_err(|_err| miette!("note {id} has a memo of the wrong length"))
+                    })
+                    .transpose()?;
+                Ok(WalletNote {
+                    id,
+                    note,
+                    witness,
+                    memo: memo.filter(|memo| !is_empty_memo(memo)),
+                })
             })
+            .collect()
+    }
+
+    /// Delete wallet notes spent more than `keep_last_n_blocks` blocks below the tip, returning
+    /// how many were deleted. Notes spent in the kept blocks remain so that those blocks can
+    /// still be disconnected.
+    pub fn prune_spent_notes(&mut self, keep_last_n_blocks: u32) -> miette::Result<usize> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let tip = Self::get_next_block_id(&tx)? - 1;
+        let Some(cutoff) = tip.checked_sub(keep_last_n_blocks) else {
+            return Ok(0);
+        };
+        tx.execute(
+            "DELETE FROM shielded_inputs
+            WHERE note_id IN (SELECT id FROM notes WHERE spent_block <= ?1)",
+            [cutoff],
+        )
+        .into_diagnostic()?;
+        let pruned = tx
+            .execute("DELETE FROM notes WHERE spent_block <= ?1", [cutoff])
+            .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+        Ok(pruned)
+    }
+
+    /// Wallet notes spent in a mined block, as note id, value and spending block.
+    pub fn list_spent_notes(&self) -> miette::Result<Vec<(u32, u64, u32)>> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT id, value, spent_block FROM notes
+                WHERE spent_block IS NOT NULL ORDER BY spent_block, id",
+            )
+            .into_diagnostic()?;
+        let notes = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
             .into_diagnostic()?
             .collect::<Result<Vec<_>, _>>()
             .into_diagnostic()?;
         Ok(notes)
     }
 
+    pub fn utxo_info(&self, id: u32) -> miette::Result<UtxoInfo> {
+        let (value, created_block, spent_block, mempool_tx) = match self.conn.query_row(
+            "SELECT value, created_block, spent_block, mempool_tx FROM utxos WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ) {
+            Ok(info) => info,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(miette!("utxo {id} does not exist"));
+            }
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        let pending_spend: bool = self
+            .conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM inputs WHERE utxo_id = ?1)",
+                [id],
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+        Ok(UtxoInfo {
+            id,
+            value,
+            created_block,
+            spent_block,
+            mempool_tx,
+            pending_spend,
+        })
+    }
+
     pub fn get_utxo_value(tx: &rusqlite::Transaction, id: u32) -> miette::Result<u64> {
         let value = tx
             .query_row("SELECT value FROM utxos WHERE id = ?1", [id], |row| {
                 row.get(0)
             })
             .into_diagnostic()?;
-        Ok(value)
+        stored_value(value)
     }
 
     pub fn store_note(
         tx: &rusqlite::Transaction,
+        account: u32,
         note: &Note,
-        witness: &IncrementalWitness<MerkleHashOrchard, 32>,
+        witness: &IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>,
+        created_block: Option<u32>,
+        memo: Option<&[u8; 512]>,
     ) -> miette::Result<()> {
         let recipient = note.recipient().to_raw_address_bytes();
         let value = note.value().inner();
         let rho = note.rho().to_bytes();
         let rseed = note.rseed().as_bytes();
         let witness_bytes = serialize_incremental_witness(witness)?;
+        let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(tx, account)?);
+        let nullifier = note.nullifier(&fvk).to_bytes();
         tx.execute(
-            "INSERT INTO notes (recipient, value, rho, rseed, witness) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (&recipient, &value, &rho, &rseed, &witness_bytes),
+            "INSERT INTO notes
+            (recipient, value, rho, rseed, witness, nullifier, created_block, memo, account)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            (
+                &recipient,
+                &value,
+                &rho,
+                &rseed,
+                &witness_bytes,
+                &nullifier,
+                created_block,
+                memo.map(|memo| &memo[..]),
+                account,
+            ),
         )
         .into_diagnostic()?;
         Ok(())
     }
 
-    pub fn get_notes(tx: &rusqlite::Transaction, block: &Block) -> miette::Result<Vec<Note>> {
-        let anchor = Db::get_bundle_anchor(tx)?;
-        let sk = Db::get_sk(tx)?;
-        let fvk = orchard::keys::FullViewingKey::from(&sk);
-        let ivk = fvk.to_ivk(zip32::Scope::External);
-        let keys = [ivk];
+    /// Hex encoded external incoming viewing key of `account`, which lets others see payments
+    /// to its addresses without being able to spend them.
+    pub fn incoming_viewing_key(&mut self, account: u32) -> miette::Result<String> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        Self::check_account(&tx, account)?;
+        let fvk = orchard::keys::FullViewingKey::from(&Self::get_sk(&tx, account)?);
+        Ok(hex::encode(fvk.to_ivk(zip32::Scope::External).to_bytes()))
+    }
+
+    fn load_block(conn: &Connection, block_id: u32) -> miette::Result<Block> {
+        let block_bytes: Vec<u8> = match conn.query_row(
+            "SELECT block FROM blocks WHERE id = ?1",
+            [block_id],
+            |row| row.get(0),
+        ) {
+            Ok(block_bytes) => block_bytes,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(miette!("block {block_id} does not exist"));
+            }
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        bincode::deserialize(&block_bytes).into_diagnostic()
+    }
+
+    /// Values and memos of the outputs in block `block_id` paying any account of this wallet,
+    /// in the order they were appended to the note commitment tree.
+    pub fn my_outputs_in_block(
+        &mut self,
+        block_id: u32,
+    ) -> miette::Result<Vec<(u64, Option<String>)>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let block = Self::load_block(&tx, block_id)?;
+        let outputs = Self::get_notes_with_memos(&tx, &block)?
+            .into_iter()
+            .map(|(_account, note, memo)| (note.value().inner(), memo_text(&memo)))
+            .collect();
+        Ok(outputs)
+    }
+
+    /// Values and memos of the outputs in block `block_id` that decrypt with the hex encoded
+    /// incoming viewing key `ivk`, which need not belong to this wallet.
+    pub fn scan_block_with_ivk(
+        &self,
+        block_id: u32,
+        ivk: &str,
+    ) -> miette::Result<Vec<(u64, Option<String>)>> {
+        let ivk: [u8; 64] = hex::decode(ivk)
+            .into_diagnostic()?
+            .try_into()
+            .map_err(|_err| miette!("wrong incoming viewing key length"))?;
+        let ivk: orchard::keys::IncomingViewingKey =
+            Option::from(orchard::keys::IncomingViewingKey::from_bytes(&ivk))
+                .ok_or_else(|| miette!("invalid incoming viewing key"))?;
+        let block = Self::load_block(&self.conn, block_id)?;
+        let mut outputs = vec![];
+        for transaction in &block.transactions {
+            if let Some(bundle) = transaction.to_unauthorized_bundle(Anchor::empty_tree()) {
+                for (_action_index, _ivk, note, _address, memo) in
+                    bundle.decrypt_outputs_with_keys(std::slice::from_ref(&ivk))
+                {
+                    outputs.push((note.value().inner(), memo_text(&memo)));
+                }
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Transactions paying this wallet whose memo contains `query`, oldest first.
+    pub fn search_memos(&mut self, query: &str) -> miette::Result<Vec<([u8; 32], String)>> {
+        let tx = self.conn.transaction().into_diagnostic()?;
+        let keys: Vec<orchard::keys::IncomingViewingKey> = Self::get_wallet_ivks(&tx)?
+            .into_iter()
+            .map(|(_account, ivk)| ivk)
+            .collect();
+        let mut matches = vec![];
+        Self::for_each_block(&tx, |_block_id, block| {
+            for transaction in &block.transactions {
+                let Some(bundle) = transaction.to_unauthorized_bundle(Anchor::empty_tree()) else {
+                    continue;
+                };
+                for (_action_index, _ivk, _note, _address, memo) in
+                    bundle.decrypt_outputs_with_keys(&keys)
+                {
+                    if let Some(text) = memo_text(&memo) {
+                        if text.contains(query) {
+                            matches.push((transaction.txid(), text));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(matches)
+    }
+
+    /// Notes in `block` paying any account of this wallet with their account and memo, in the
+    /// order their commitments are appended to the note commitment tree: by transaction, then
+    /// by action index.
+    pub fn get_notes_with_memos(
+        tx: &rusqlite::Transaction,
+        block: &Block,
+    ) -> miette::Result<Vec<(u32, Note, [u8; 512])>> {
+        let ivks = Self::get_wallet_ivks(tx)?;
+        let keys: Vec<orchard::keys::IncomingViewingKey> =
+            ivks.iter().map(|(_account, ivk)| ivk.clone()).collect();
         let mut decrypted_notes = vec![];
         for transaction in &block.transactions {
-            if let Some(bundle) = transaction.to_bundle(anchor) {
-                let notes = bundle.decrypt_outputs_with_keys(&keys);
-                for (_action_index, _ivk, note, _address, _memo) in &notes {
-                    decrypted_notes.push(*note);
+            // Decryption doesn't depend on the anchor.
+            if let Some(bundle) = transaction.to_unauthorized_bundle(Anchor::empty_tree()) {
+                let mut notes = bundle.decrypt_outputs_with_keys(&keys);
+                notes.sort_by_key(|(action_index, _ivk, _note, _address, _memo)| *action_index);
+                for (_action_index, ivk, note, _address, memo) in &notes {
+                    let (account, _ivk) = ivks
+                        .iter()
+                        .find(|(_account, key)| key.to_bytes() == ivk.to_bytes())
+                        .expect("decrypted with one of the wallet keys");
+                    decrypted_notes.push((*account, *note, *memo));
                 }
             }
         }
@@ -848,39 +3647,305 @@ impl Db {
     }
 }
 
+/// Report a database held by another process as such rather than as a raw SQLite error.
+fn lock_error(err: rusqlite::Error) -> miette::Report {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+            miette!("database is locked by another process (is another instance running?)")
+        }
+        _ => miette!(err),
+    }
+}
+
+fn address_from_bytes(bytes: Vec<u8>) -> miette::Result<Address> {
+    let bytes: [u8; 43] = bytes
+        .try_into()
+        .map_err(|_err| miette!("wrong shielded address length"))?;
+    Option::from(Address::from_raw_address_bytes(&bytes))
+        .ok_or_else(|| miette!("invalid shielded address"))
+}
+
+/// Wallet note `id` rebuilt from the columns of its row in the notes table.
+fn note_from_parts(
+    id: u32,
+    recipient: Vec<u8>,
+    value: u64,
+    rho: Vec<u8>,
+    rseed: Vec<u8>,
+) -> miette::Result<Note> {
+    let recipient: [u8; 43] = recipient
+        .try_into()
+        .map_err(|_err| miette!("note {id} has a recipient address of the wrong length"))?;
+    let recipient = Option::from(Address::from_raw_address_bytes(&recipient))
+        .ok_or_else(|| miette!("note {id} has a malformed recipient address"))?;
+    let value = NoteValue::from_raw(value);
+    let rho: [u8; 32] = rho
+        .try_into()
+        .map_err(|_err| miette!("note {id} has a rho of the wrong length"))?;
+    let rho = Option::from(Rho::from_bytes(&rho))
+        .ok_or_else(|| miette!("note {id} has a malformed rho"))?;
+    let rseed: [u8; 32] = rseed
+        .try_into()
+        .map_err(|_err| miette!("note {id} has an rseed of the wrong length"))?;
+    let rseed = Option::from(RandomSeed::from_bytes(rseed, &rho))
+        .ok_or_else(|| miette!("note {id} has a malformed rseed"))?;
+    Option::from(Note::from_parts(recipient, value, rho, rseed))
+        .ok_or_else(|| miette!("note {id} can't be rebuilt from its stored parts"))
+}
+
+/// Memo holding `text` as UTF-8, zero padded to 512 bytes (ZIP 302).
+pub fn memo_from_text(text: &str) -> miette::Result<[u8; 512]> {
+    let bytes = text.as_bytes();
+    if bytes.len() > 512 {
+        return Err(miette!(
+            "memo is {} bytes long, memos are at most 512 bytes",
+            bytes.len()
+        ));
+    }
+    let mut memo = [0u8; 512];
+    memo[..bytes.len()].copy_from_slice(bytes);
+    Ok(memo)
+}
+
+/// Text of a memo, `None` for empty memos and memos that aren't UTF-8 text (ZIP 302).
+pub fn memo_text(memo: &[u8; 512]) -> Option<String> {
+    if memo[0] > 0xF4 {
+        return None;
+    }
+    let end = memo.iter().rposition(|byte| *byte != 0)? + 1;
+    String::from_utf8(memo[..end].to_vec()).ok()
+}
+
+/// Values are stored in INTEGER columns, which SQLite holds as `i64`, and handled as `u64` raw
+/// units everywhere else. A stored value that doesn't fit, like a `u64` above `i64::MAX` written
+/// with a wrapping cast, is an error rather than a negative amount.
+fn stored_value(value: i64) -> miette::Result<u64> {
+    u64::try_from(value).map_err(|_err| miette!("stored value {value} is out of range"))
+}
+
+/// A value as a signed amount, for arithmetic with Orchard value balances and change.
+fn signed_value(value: u64) -> miette::Result<i64> {
+    i64::try_from(value).map_err(|_err| miette!("value {value} is out of range"))
+}
+
+/// Sum of values as a signed amount, an error instead of wrapping on overflow.
+fn sum_values(values: impl IntoIterator<Item = u64>) -> miette::Result<i64> {
+    values.into_iter().try_fold(0i64, |sum, value| {
+        sum.checked_add(signed_value(value)?)
+            .ok_or_else(|| miette!("sum of values is out of range"))
+    })
+}
+
+/// Whether `memo` carries nothing: all zeros, or the ZIP 302 "no memo" marker 0xF6 followed by
+/// zeros, which is what notes created without a memo get.
+fn is_empty_memo(memo: &[u8; 512]) -> bool {
+    matches!(memo[0], 0 | 0xF6) && memo[1..].iter().all(|byte| *byte == 0)
+}
+
+/// Memo for display: its text when it's UTF-8, otherwise hex without the trailing zero padding.
+pub fn memo_display(memo: &[u8; 512]) -> String {
+    memo_text(memo).unwrap_or_else(|| {
+        let end = memo
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |end| end + 1);
+        hex::encode(&memo[..end])
+    })
+}
+
+pub fn current_timestamp() -> miette::Result<i64> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .into_diagnostic()?
+        .as_secs();
+    Ok(timestamp as i64)
+}
+
+/// Encode raw shielded address bytes for display.
+pub fn encode_shielded_address(params: &NetworkParams, address_bytes: &[u8]) -> String {
+    bs58::encode(address_bytes)
+        .with_check_version(params.address_version)
+        .into_string()
+}
+
+/// Addresses of other networks are rejected so funds can't be sent where they can't arrive.
+fn decode_shielded_address(params: &NetworkParams, address: &str) -> miette::Result<Address> {
+    let address = bs58::decode(address)
+        .with_check(None)
+        .into_vec()
+        .into_diagnostic()?;
+    let Some((&version, address)) = address.split_first() else {
+        return Err(miette!("empty address"));
+    };
+    if version != params.address_version {
+        return Err(miette!(
+            "address belongs to the network with version byte {version:#04x}, this network uses {:#04x}",
+            params.address_version
+        ));
+    }
+    let address: [u8; 43] = address
+        .try_into()
+        .map_err(|_err| miette!("wrong address length"))?;
+    Option::from(Address::from_raw_address_bytes(&address))
+        .ok_or_else(|| miette!("invalid shielded address"))
+}
+
+/// Transaction encoding from before transactions carried their Orchard authorization.
+#[derive(Deserialize)]
+struct LegacyTransaction {
+    inputs: Vec<u32>,
+    outputs: Vec<Output>,
+    actions: Vec<crate::types::Action>,
+    value_balance_orchard: i64,
+}
+
+#[derive(Deserialize)]
+struct LegacyBlock {
+    transactions: Vec<LegacyTransaction>,
+}
+
+impl From<LegacyTransaction> for crate::types::Transaction {
+    fn from(legacy: LegacyTransaction) -> Self {
+        Self {
+            inputs: legacy.inputs,
+            outputs: legacy.outputs,
+            actions: legacy.actions,
+            value_balance_orchard: legacy.value_balance_orchard,
+            authorization: None,
+        }
+    }
+}
+
+/// Re-encode stored blocks and mempool transactions with an empty authorization and rehash
+/// the blocks, whose bytes change. Txids don't cover the authorization, so they and the
+/// transaction labels keyed by them stay the same.
+fn add_transaction_authorization(tx: &rusqlite::Transaction) -> HookResult {
+    let hook_error = |err: bincode::Error| HookError::Hook(err.to_string());
+    let blocks = {
+        let mut statement =
+            tx.prepare("SELECT id, timestamp, issuance, block, hash FROM blocks ORDER BY id")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                    row.get::<_, Option<Vec<u8>>>(4)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+    let mut previous_hash = None;
+    for (block_id, timestamp, issuance, block_bytes, hash) in blocks {
+        let legacy: LegacyBlock = bincode::deserialize(&block_bytes).map_err(hook_error)?;
+        let block = Block {
+            transactions: legacy.transactions.into_iter().map(Into::into).collect(),
+        };
+        let block_bytes = bincode::serialize(&block).map_err(hook_error)?;
+        // Like store_block, blocks without a hash link the next block to the zero hash.
+        let hash = hash.map(|_hash| {
+            block_hash(
+                &previous_hash.unwrap_or([0; 32]),
+                timestamp.unwrap_or(0),
+                issuance,
+                &block_bytes,
+            )
+        });
+        tx.execute(
+            "UPDATE blocks SET block = ?1, hash = ?2 WHERE id = ?3",
+            (&block_bytes, hash, block_id),
+        )?;
+        previous_hash = hash;
+    }
+    let transactions = {
+        let mut statement = tx.prepare("SELECT id, tx FROM transactions")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+    for (id, transaction_bytes) in transactions {
+        let legacy: LegacyTransaction =
+            bincode::deserialize(&transaction_bytes).map_err(hook_error)?;
+        let transaction = crate::types::Transaction::from(legacy);
+        let transaction_bytes = bincode::serialize(&transaction).map_err(hook_error)?;
+        tx.execute(
+            "UPDATE transactions SET tx = ?1 WHERE id = ?2",
+            (transaction_bytes, id),
+        )?;
+    }
+    Ok(())
+}
+
+/// Blocks commit to their parent, so equal hashes mean equal chains up to that height.
+fn block_hash(
+    previous_hash: &[u8; 32],
+    timestamp: i64,
+    issuance: u64,
+    block_bytes: &[u8],
+) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(previous_hash);
+    hasher.update(&timestamp.to_le_bytes());
+    hasher.update(&issuance.to_le_bytes());
+    hasher.update(block_bytes);
+    *hasher.finalize().as_bytes()
+}
+
+fn seed_cipher(password: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), salt, SEED_KDF_ROUNDS, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Inverse of the frontier serialization in `store_block`: position, leaf and ommers.
+fn deserialize_frontier(bytes: &[u8]) -> miette::Result<NonEmptyFrontier<MerkleHashOrchard>> {
+    let (position, leaf, ommers): (u64, MerkleHashOrchard, Vec<MerkleHashOrchard>) =
+        bincode::deserialize(bytes)
+            .map_err(|err| miette!("failed to deserialize stored frontier: {err}"))?;
+    let ommer_count = ommers.len();
+    NonEmptyFrontier::from_parts(Position::from(position), leaf, ommers).map_err(|_err| {
+        miette!("stored frontier at position {position} is malformed, it has {ommer_count} ommers")
+    })
+}
+
+/// Serialized form of a `CommitmentTree`: left, right and parents.
+type CommitmentTreeParts = (
+    Option<MerkleHashOrchard>,
+    Option<MerkleHashOrchard>,
+    Vec<Option<MerkleHashOrchard>>,
+);
+
 fn deserialize_incremental_witness(
     bytes: &[u8],
-) -> miette::Result<IncrementalWitness<MerkleHashOrchard, 32>> {
+) -> miette::Result<IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>> {
     let (tree, filled, cursor): (
-        (
-            Option<MerkleHashOrchard>,
-            Option<MerkleHashOrchard>,
-            Vec<Option<MerkleHashOrchard>>,
-        ),
+        CommitmentTreeParts,
         Vec<MerkleHashOrchard>,
-        Option<(
-            Option<MerkleHashOrchard>,
-            Option<MerkleHashOrchard>,
-            Vec<Option<MerkleHashOrchard>>,
-        )>,
+        Option<CommitmentTreeParts>,
     ) = bincode::deserialize(bytes).into_diagnostic()?;
-    let tree: CommitmentTree<MerkleHashOrchard, 32> = {
+    let tree: CommitmentTree<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH> = {
         let (left, right, parents) = tree;
         CommitmentTree::from_parts(left, right, parents)
-            .expect("failed to construct commitment tree from parts")
+            .map_err(|()| miette!("witness tree has more parents than the tree depth"))?
     };
-    let cursor: Option<CommitmentTree<MerkleHashOrchard, 32>> =
-        cursor.map(|(left, right, parents)| {
+    let cursor: Option<CommitmentTree<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>> = cursor
+        .map(|(left, right, parents)| {
             CommitmentTree::from_parts(left, right, parents)
-                .expect("failed to construct commitment tree from parts")
-        });
-    let witness: IncrementalWitness<MerkleHashOrchard, 32> =
+                .map_err(|()| miette!("witness cursor has more parents than the tree depth"))
+        })
+        .transpose()?;
+    let witness: IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH> =
         IncrementalWitness::from_parts(tree, filled, cursor);
     Ok(witness)
 }
 
 fn serialize_incremental_witness(
-    witness: &IncrementalWitness<MerkleHashOrchard, 32>,
+    witness: &IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>,
 ) -> miette::Result<Vec<u8>> {
     let tree = witness.tree();
     let tree = (tree.left(), tree.right(), tree.parents());
@@ -891,3 +3956,6 @@ fn serialize_incremental_witness(
     let bytes = bincode::serialize(&parts).into_diagnostic()?;
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests;
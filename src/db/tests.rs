@@ -0,0 +1,2080 @@
+use super::*;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// A database file of its own for tests that reopen or compare databases.
+fn temp_path() -> PathBuf {
+    static CREATED: AtomicU32 = AtomicU32::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "orchard_sandbox_{}_{}.db3",
+        std::process::id(),
+        CREATED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn new_db() -> Db {
+    Db::new_in_memory().unwrap()
+}
+
+fn mnemonic_phrase(db: &mut Db) -> String {
+    let tx = db.conn.transaction().unwrap();
+    Db::get_mnemonic(&tx).unwrap().phrase().to_string()
+}
+
+fn conjure(db: &Db, value: u64) -> u32 {
+    db.conjure_utxo(value).unwrap();
+    db.conn.last_insert_rowid() as u32
+}
+
+/// Submit a transaction spending `utxo_id` into a utxo of `value`, paying the rest as fee.
+/// Returns the id of its pending utxo.
+fn submit_transfer(db: &mut Db, utxo_id: u32, value: u64) -> u32 {
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(value).unwrap();
+    db.submit_transaction().unwrap()[0]
+}
+
+/// Mine a block holding a single transfer of a conjured utxo.
+fn mine_filler_block(db: &mut Db) {
+    let utxo_id = conjure(db, 1000);
+    submit_transfer(db, utxo_id, 1000);
+    db.mine().unwrap();
+}
+
+/// Submit a transaction shielding a conjured utxo into a note of `value` to this wallet.
+fn submit_shielding(db: &mut Db, value: u64) {
+    let utxo_id = conjure(db, value);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(0, None, value, None).unwrap();
+    db.submit_transaction().unwrap();
+}
+
+/// Mine the mempool, expecting its only transaction to be rejected, and return the reason.
+fn mine_rejected(db: &mut Db) -> String {
+    let result = db.mine().unwrap();
+    assert_eq!(result.block_id, None);
+    assert_eq!(result.rejected.len(), 1);
+    result.rejected[0].reason.clone()
+}
+
+#[test]
+fn utxo_info_shows_creation_and_spend() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(900).unwrap();
+    assert!(db.utxo_info(utxo_id).unwrap().pending_spend);
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+
+    let info = db.utxo_info(utxo_id).unwrap();
+    assert_eq!(info.value, 1000);
+    assert_eq!(info.created_block, None);
+    assert_eq!(info.spent_block, Some(1));
+    let (child_id, _value, _locked) = db.get_utxos().unwrap().pop().unwrap();
+    let child = db.utxo_info(child_id).unwrap();
+    assert_eq!(child.value, 900);
+    assert_eq!(child.created_block, Some(1));
+    assert_eq!(child.spent_block, None);
+    assert!(db.utxo_info(child_id + 1).is_err());
+}
+
+#[test]
+fn utxos_mature_after_transparent_maturity_blocks() {
+    let mut db = new_db();
+    db.params.transparent_maturity = 2;
+    mine_filler_block(&mut db);
+    let (utxo_id, _value, _locked) = db.get_utxos().unwrap().pop().unwrap();
+    assert_eq!(db.utxo_info(utxo_id).unwrap().created_block, Some(1));
+
+    let err = db.spend_utxo(utxo_id).unwrap_err();
+    assert!(err.to_string().contains("immature"));
+    mine_filler_block(&mut db);
+    assert!(db.spend_utxo(utxo_id).is_err());
+    mine_filler_block(&mut db);
+    db.spend_utxo(utxo_id).unwrap();
+}
+
+#[test]
+fn all_nullifiers_lists_every_revealed_nullifier() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    submit_shielding(&mut db, 2000);
+    db.mine().unwrap();
+
+    let block: Vec<u8> = db
+        .conn
+        .query_row("SELECT block FROM blocks WHERE id = 1", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let block: Block = bincode::deserialize(&block).unwrap();
+    assert_eq!(block.transactions.len(), 2);
+    let revealed: Vec<([u8; 32], Option<u32>)> = block
+        .nullifiers()
+        .iter()
+        .map(|nullifier| (nullifier.to_bytes(), Some(1)))
+        .collect();
+    assert_eq!(db.all_nullifiers().unwrap(), revealed);
+}
+
+#[test]
+fn encrypted_seed_opens_only_with_its_password() {
+    let path = temp_path();
+    let mut db = Db::new_encrypted(&path, "correct horse").unwrap();
+    let phrase = mnemonic_phrase(&mut db);
+    let plaintext_rows: u32 = db
+        .conn
+        .query_row("SELECT COUNT(*) FROM main.wallet_seed", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(plaintext_rows, 0);
+    drop(db);
+
+    assert!(Db::is_encrypted(&path).unwrap());
+    let err = Db::new_encrypted(&path, "wrong password").err().unwrap();
+    assert!(err.to_string().contains("wrong wallet password"));
+    assert!(Db::new_at(&path).is_err());
+    let mut db = Db::new_encrypted(&path, "correct horse").unwrap();
+    assert_eq!(mnemonic_phrase(&mut db), phrase);
+}
+
+#[test]
+fn change_notes_go_to_distinct_internal_addresses() {
+    let mut db = new_db();
+    for value in [1000, 2000] {
+        let utxo_id = conjure(&db, value);
+        db.spend_utxo(utxo_id).unwrap();
+        db.create_change_note(0, value, None).unwrap();
+        db.submit_transaction().unwrap();
+    }
+    db.mine().unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    let fvk = orchard::keys::FullViewingKey::from(&Db::get_sk(&tx, 0).unwrap());
+    drop(tx);
+    let recipients: Vec<Address> = db
+        .get_wallet_notes()
+        .unwrap()
+        .iter()
+        .map(|note| note.note.recipient())
+        .collect();
+    assert_eq!(recipients.len(), 2);
+    assert_ne!(recipients[0], recipients[1]);
+    for recipient in recipients {
+        assert_eq!(
+            fvk.scope_for_address(&recipient),
+            Some(zip32::Scope::Internal)
+        );
+    }
+}
+
+#[test]
+fn witnesses_are_taken_at_the_position_of_their_note() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 500);
+    db.mine().unwrap();
+    let utxo_id = conjure(&db, 3000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(0, None, 1000, None).unwrap();
+    db.create_note(0, None, 2000, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+
+    let mut leaves = vec![];
+    let mut first_block_leaves = 0;
+    for block_id in [1, 2] {
+        let block: Vec<u8> = db
+            .conn
+            .query_row(
+                "SELECT block FROM blocks WHERE id = ?1",
+                [block_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let block: Block = bincode::deserialize(&block).unwrap();
+        leaves.extend(block.extracted_note_commitments());
+        if block_id == 1 {
+            first_block_leaves = leaves.len();
+        }
+    }
+    let tx = db.conn.transaction().unwrap();
+    let root = Db::get_last_frontier(&tx)
+        .unwrap()
+        .unwrap()
+        .root(Some(Level::from(32)));
+    drop(tx);
+
+    let notes = db.get_wallet_notes().unwrap();
+    assert_eq!(notes.len(), 3);
+    let mut second_block_notes = 0;
+    for WalletNote { note, witness, .. } in notes {
+        let position = u64::from(witness.witnessed_position()) as usize;
+        assert_eq!(
+            leaves[position],
+            ExtractedNoteCommitment::from(note.commitment())
+        );
+        if position >= first_block_leaves {
+            assert_eq!(witness.root(), root);
+            second_block_notes += 1;
+        }
+    }
+    assert_eq!(second_block_notes, 2);
+}
+
+#[test]
+fn child_spending_an_unmined_parent_is_mined_with_it() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    let parent_utxo = submit_transfer(&mut db, utxo_id, 900);
+    assert!(db
+        .get_utxos()
+        .unwrap()
+        .iter()
+        .all(|(id, _, _)| *id != parent_utxo));
+    let child_utxo = submit_transfer(&mut db, parent_utxo, 800);
+    db.mine().unwrap();
+
+    let parent = db.utxo_info(parent_utxo).unwrap();
+    assert_eq!(parent.created_block, Some(1));
+    assert_eq!(parent.spent_block, Some(1));
+    assert_eq!(parent.mempool_tx, None);
+    let child = db.utxo_info(child_utxo).unwrap();
+    assert_eq!(child.created_block, Some(1));
+    assert_eq!(child.spent_block, None);
+    assert_eq!(db.get_utxos().unwrap(), vec![(child_utxo, 800, false)]);
+}
+
+const FIXED_PHRASE: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+fn set_phrase(db: &Db, phrase: &str) {
+    db.conn
+        .execute("UPDATE wallet_seed SET phrase = ?1", [phrase])
+        .unwrap();
+}
+
+#[test]
+fn fingerprint_is_stable_for_a_fixed_mnemonic() {
+    let mut first = new_db();
+    let mut second = new_db();
+    let mut other = new_db();
+    set_phrase(&first, FIXED_PHRASE);
+    set_phrase(&second, FIXED_PHRASE);
+    let fingerprint = first.wallet_fingerprint().unwrap();
+    assert_eq!(fingerprint.len(), 16);
+    assert_eq!(first.wallet_fingerprint().unwrap(), fingerprint);
+    assert_eq!(second.wallet_fingerprint().unwrap(), fingerprint);
+    assert_ne!(other.wallet_fingerprint().unwrap(), fingerprint);
+}
+
+#[test]
+fn blocks_store_their_mining_timestamp() {
+    let mut db = new_db();
+    let timestamps = [1_700_000_000, 1_700_000_060, 1_700_000_120];
+    for timestamp in timestamps {
+        let utxo_id = conjure(&db, 1000);
+        submit_transfer(&mut db, utxo_id, 1000);
+        db.mine_at(timestamp, false).unwrap();
+    }
+    let mut statement = db
+        .conn
+        .prepare("SELECT timestamp FROM blocks ORDER BY id")
+        .unwrap();
+    let stored: Vec<i64> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(stored, timestamps);
+}
+
+#[test]
+fn blocks_earlier_than_their_parent_are_rejected() {
+    let mut db = new_db();
+    let first = conjure(&db, 1000);
+    let second = conjure(&db, 1000);
+    submit_transfer(&mut db, first, 1000);
+    db.mine_at(1_700_000_000, false).unwrap();
+
+    submit_transfer(&mut db, second, 1000);
+    let err = db.mine_at(1_699_999_999, false).unwrap_err();
+    assert!(err.to_string().contains("earlier than the previous block"));
+    db.mine_at(1_700_000_000, false).unwrap();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 1000);
+    db.mine_at(1_700_000_001, false).unwrap();
+}
+
+#[test]
+fn staged_transaction_survives_reopening() {
+    let path = temp_path();
+    let mut db = Db::new_at(&path).unwrap();
+    assert!(db.pending_transaction_state().unwrap().is_empty());
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(300).unwrap();
+    db.create_note(0, None, 600, None).unwrap();
+    drop(db);
+
+    let mut db = Db::new_at(&path).unwrap();
+    let state = db.pending_transaction_state().unwrap();
+    assert_eq!(state.inputs, vec![utxo_id]);
+    let outputs: Vec<u64> = state.outputs.iter().map(|output| output.value).collect();
+    assert_eq!(outputs, vec![300]);
+    assert!(state.shielded_inputs.is_empty());
+    assert_eq!(state.shielded_outputs.len(), 1);
+    assert_eq!(state.shielded_outputs[0].value, 600);
+    db.submit_transaction().unwrap();
+    assert!(db.pending_transaction_state().unwrap().is_empty());
+}
+
+#[test]
+fn coinbase_issuance_is_audited() {
+    let mut db = new_db();
+    db.params.initial_block_reward = 1000;
+    db.mine_coinbase(None).unwrap();
+    let audit = db.supply_audit().unwrap();
+    assert_eq!(audit.issued, 1000);
+    assert_eq!(audit.conjured, 0);
+    assert_eq!(audit.transparent, 1000);
+    assert!(audit.is_balanced());
+
+    let address = db.get_new_address(0).unwrap();
+    let address = encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+    db.mine_coinbase(Some(address)).unwrap();
+    let audit = db.supply_audit().unwrap();
+    assert_eq!(audit.issued, 2000);
+    assert_eq!(audit.shielded, 1000);
+    assert!(audit.is_balanced());
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+}
+
+/// Mine a block at `timestamp` transferring a conjured utxo into a utxo of `value`. Blocks
+/// mined this way are identical in every database with the same history.
+fn mine_transfer_at(db: &mut Db, timestamp: i64, value: u64) {
+    let utxo_id = conjure(db, 1000);
+    submit_transfer(db, utxo_id, value);
+    db.mine_at(timestamp, false).unwrap();
+}
+
+#[test]
+fn compare_chains_finds_the_divergence_height() {
+    let path = temp_path();
+    let other_path = temp_path();
+    let mut db = Db::new_at(&path).unwrap();
+    let mut other = Db::new_at(&other_path).unwrap();
+    for db in [&mut db, &mut other] {
+        mine_transfer_at(db, 1_700_000_000, 1000);
+        mine_transfer_at(db, 1_700_000_001, 1000);
+    }
+    let comparison = Db::compare_chains(&path, &other_path).unwrap();
+    assert_eq!(comparison.divergence_height, None);
+    assert_eq!(db.tip_summary().unwrap(), other.tip_summary().unwrap());
+
+    mine_transfer_at(&mut db, 1_700_000_002, 900);
+    mine_transfer_at(&mut other, 1_700_000_002, 800);
+    let comparison = Db::compare_chains(&path, &other_path).unwrap();
+    assert_eq!(comparison.height, 3);
+    assert_eq!(comparison.other_height, 3);
+    assert_eq!(comparison.divergence_height, Some(3));
+}
+
+/// Submit a transaction shielding a conjured utxo into a note to this wallet carrying `memo`.
+fn submit_memo_note(db: &mut Db, value: u64, memo: &str) {
+    let utxo_id = conjure(db, value);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(0, None, value, Some(memo_from_text(memo).unwrap()))
+        .unwrap();
+    db.submit_transaction().unwrap();
+}
+
+#[test]
+fn search_memos_finds_every_matching_note() {
+    let mut db = new_db();
+    submit_memo_note(&mut db, 1000, "invoice-1");
+    submit_memo_note(&mut db, 2000, "invoice-2");
+    submit_memo_note(&mut db, 3000, "refund");
+    db.mine().unwrap();
+
+    let memos: Vec<String> = db
+        .search_memos("invoice")
+        .unwrap()
+        .into_iter()
+        .map(|(_txid, memo)| memo)
+        .collect();
+    assert_eq!(memos, vec!["invoice-1", "invoice-2"]);
+    assert!(db.search_memos("receipt").unwrap().is_empty());
+}
+
+#[test]
+fn value_balance_without_actions_is_rejected() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 1500);
+    let bytes: Vec<u8> = db
+        .conn
+        .query_row("SELECT tx FROM transactions", [], |row| row.get(0))
+        .unwrap();
+    let mut transaction: crate::types::Transaction = bincode::deserialize(&bytes).unwrap();
+    assert!(transaction.actions.is_empty());
+    transaction.value_balance_orchard = 500;
+
+    let tx = db.conn.transaction().unwrap();
+    let err = Db::validate_transaction(&tx, &db.params, &db.keys, &transaction).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("does not match bundle value balance"));
+    tx.execute(
+        "UPDATE transactions SET tx = ?1",
+        [bincode::serialize(&transaction).unwrap()],
+    )
+    .unwrap();
+    tx.commit().unwrap();
+    let reason = mine_rejected(&mut db);
+    assert!(
+        reason.contains("does not match bundle value balance"),
+        "{reason}"
+    );
+}
+
+#[test]
+fn status_summarizes_a_small_chain() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    db.params.initial_block_reward = 500;
+    db.mine_coinbase(None).unwrap();
+    let (coinbase_utxo, _value, _locked) = db.get_utxos().unwrap().pop().unwrap();
+    submit_transfer(&mut db, coinbase_utxo, 400);
+
+    let block: Vec<u8> = db
+        .conn
+        .query_row("SELECT block FROM blocks WHERE id = 1", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let block: Block = bincode::deserialize(&block).unwrap();
+    let status = db.status().unwrap();
+    assert!(status.schema_version > 0);
+    assert_eq!(status.height, 2);
+    assert_eq!(status.mempool_size, 1);
+    assert_eq!(status.transparent_value, 500);
+    assert_eq!(status.shielded_value, 1000);
+    assert_eq!(status.nullifier_count as usize, block.nullifiers().len());
+    assert_eq!(
+        status.tree_size as usize,
+        block.extracted_note_commitments().len()
+    );
+}
+
+#[test]
+fn block_reward_halves_at_the_halving_interval() {
+    let params = NetworkParams {
+        initial_block_reward: 100,
+        halving_interval: 10,
+        ..NetworkParams::default()
+    };
+    assert_eq!(params.block_reward(1), 100);
+    assert_eq!(params.block_reward(10), 100);
+    assert_eq!(params.block_reward(11), 50);
+    assert_eq!(params.block_reward(21), 25);
+    assert_eq!(params.block_reward(10 * 64 + 1), 0);
+
+    let mut db = new_db();
+    db.params.halving_interval = 2;
+    for _ in 0..3 {
+        db.mine_coinbase(None).unwrap();
+    }
+    let reward = db.params.initial_block_reward;
+    let audit = db.supply_audit().unwrap();
+    assert_eq!(audit.issued, reward + reward + reward / 2);
+    assert!(audit.is_balanced());
+}
+
+#[test]
+fn foreign_network_addresses_are_rejected() {
+    let mut db = new_db();
+    let address = db.get_new_address(0).unwrap().to_raw_address_bytes();
+    let foreign_params = NetworkParams {
+        address_version: db.params.address_version + 1,
+        ..NetworkParams::default()
+    };
+    let foreign = encode_shielded_address(&foreign_params, &address);
+    let err = db.create_note(0, Some(foreign), 1000, None).unwrap_err();
+    assert!(err.to_string().contains("version byte"));
+    assert!(db.pending_transaction_state().unwrap().is_empty());
+
+    let own = encode_shielded_address(&db.params, &address);
+    db.create_note(0, Some(own), 1000, None).unwrap();
+    let state = db.pending_transaction_state().unwrap();
+    assert_eq!(state.shielded_outputs.len(), 1);
+    assert_eq!(state.shielded_outputs[0].recipient, address.to_vec());
+    assert_eq!(state.shielded_outputs[0].value, 1000);
+}
+
+#[test]
+fn for_each_block_visits_blocks_in_height_order() {
+    let mut db = new_db();
+    for _ in 0..100 {
+        db.mine_coinbase(None).unwrap();
+    }
+    let tx = db.conn.transaction().unwrap();
+    let mut heights = vec![];
+    Db::for_each_block(&tx, |block_id, block| {
+        assert_eq!(block.transactions.len(), 1);
+        heights.push(block_id);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(heights, (1..=100).collect::<Vec<u32>>());
+}
+
+#[test]
+fn corrupted_chain_exports_are_rejected() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    db.mine_coinbase(None).unwrap();
+    let path = temp_path().with_extension("chain");
+    assert_eq!(db.export_chain(&path).unwrap(), 2);
+
+    let mut other = new_db();
+    assert_eq!(other.import_chain(&path).unwrap(), 2);
+    assert_eq!(other.tip_summary().unwrap(), db.tip_summary().unwrap());
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    let err = new_db().import_chain(&path).unwrap_err();
+    assert!(err.to_string().contains("corrupt or truncated export file"));
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Submit a transaction spending the wallet note `note_id` into a new note to this wallet. The
+/// note's witness must resolve to the current bundle anchor.
+fn submit_note_spend(db: &mut Db, note_id: u32) {
+    let recipient = db.get_new_address(0).unwrap();
+    let transaction = {
+        let tx = db.conn.transaction().unwrap();
+        let value = Db::get_note(&tx, note_id).unwrap().value().inner();
+        let mut builder = crate::builder::TransactionBuilder::new(&tx, &db.params).unwrap();
+        builder.add_note_spend(&tx, &db.params, note_id).unwrap();
+        builder.add_shielded_output(recipient, value, None).unwrap();
+        builder.build(&db.keys).unwrap()
+    };
+    db.submit_built(&transaction).unwrap();
+}
+
+#[test]
+fn note_nullifier_matches_the_revealed_nullifier() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    // Bundles are anchored three blocks back.
+    for _ in 0..3 {
+        mine_filler_block(&mut db);
+    }
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    let nullifier = db.note_nullifier(note_id).unwrap();
+    assert!(!db.is_nullifier_spent(&nullifier).unwrap());
+
+    submit_note_spend(&mut db, note_id);
+    db.mine().unwrap();
+    assert!(db.is_nullifier_spent(&nullifier).unwrap());
+    assert!(db.all_nullifiers().unwrap().contains(&(nullifier, Some(5))));
+    assert!(db.note_nullifier(note_id + 10).is_err());
+}
+
+#[test]
+fn tampered_proofs_are_rejected_when_verifying_proofs() {
+    let mut db = new_db();
+    db.params.verify_proofs = true;
+    let utxo_id = conjure(&db, 1000);
+    let recipient = db.get_new_address(0).unwrap();
+    let transaction = {
+        let tx = db.conn.transaction().unwrap();
+        let mut builder = crate::builder::TransactionBuilder::new(&tx, &db.params).unwrap();
+        builder.add_transparent_input(utxo_id);
+        builder.add_shielded_output(recipient, 900, None).unwrap();
+        builder.build(&db.keys).unwrap()
+    };
+
+    let mut tampered = transaction.clone();
+    tampered.authorization.as_mut().unwrap().proof[0] ^= 1;
+    db.submit_built(&tampered).unwrap();
+    let err = db
+        .mine_selected(&[tampered.txid()])
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("transaction 0 is invalid: Orchard proof is invalid"),
+        "{err}"
+    );
+
+    let mut other = new_db();
+    other.params.verify_proofs = true;
+    conjure(&other, 1000);
+    other.submit_built(&transaction).unwrap();
+    other.mine_selected(&[transaction.txid()]).unwrap();
+    assert_eq!(other.status().unwrap().height, 1);
+}
+
+#[test]
+fn spent_notes_list_their_spending_block() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    for _ in 0..3 {
+        mine_filler_block(&mut db);
+    }
+    assert!(db.list_spent_notes().unwrap().is_empty());
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    submit_note_spend(&mut db, note_id);
+    db.mine().unwrap();
+
+    assert_eq!(db.list_spent_notes().unwrap(), vec![(note_id, 1000, 5)]);
+    assert_eq!(db.get_total_shielded_value(None).unwrap(), 1000);
+}
+
+#[test]
+fn inputs_listed_twice_are_rejected() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(1500).unwrap();
+    db.submit_transaction().unwrap();
+    let reason = mine_rejected(&mut db);
+    assert!(reason.contains("spent more than once"), "{reason}");
+    assert_eq!(db.utxo_info(utxo_id).unwrap().spent_block, None);
+}
+
+#[test]
+fn pending_transactions_are_listed_until_mined() {
+    let mut db = new_db();
+    let first = conjure(&db, 1000);
+    let second = conjure(&db, 2000);
+    submit_transfer(&mut db, first, 900);
+    submit_transfer(&mut db, second, 1800);
+
+    let mempool = db.get_pending_transactions().unwrap();
+    let inputs: Vec<Vec<u32>> = mempool
+        .iter()
+        .map(|(_id, transaction)| transaction.inputs.clone())
+        .collect();
+    assert_eq!(inputs, vec![vec![first], vec![second]]);
+    assert_eq!(mempool[1].1.outputs[0].value, 1800);
+    db.mine().unwrap();
+    assert!(db.get_pending_transactions().unwrap().is_empty());
+}
+
+#[test]
+fn transaction_labels_persist_after_mining() {
+    let path = temp_path();
+    let mut db = Db::new_at(&path).unwrap();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    let (_id, transaction) = db.get_pending_transactions().unwrap().pop().unwrap();
+    let txid = transaction.txid();
+    assert_eq!(db.get_transaction_label(&txid).unwrap(), None);
+    db.label_transaction(&txid, "draft").unwrap();
+    db.label_transaction(&txid, "test payment").unwrap();
+    db.mine().unwrap();
+    drop(db);
+
+    let db = Db::new_at(&path).unwrap();
+    assert_eq!(
+        db.get_transaction_label(&txid).unwrap().as_deref(),
+        Some("test payment")
+    );
+}
+
+#[test]
+fn conjure_requires_allow_conjure() {
+    let mut db = new_db();
+    db.params.allow_conjure = false;
+    let err = db.conjure_utxo(1000).unwrap_err();
+    assert!(err.to_string().contains("disabled"));
+    assert!(db.get_utxos().unwrap().is_empty());
+
+    db.params.allow_conjure = true;
+    let utxo_id = conjure(&db, 1000);
+    assert_eq!(db.get_utxos().unwrap(), vec![(utxo_id, 1000, false)]);
+}
+
+/// Mine a coinbase paying the block reward to a note at a fresh address of this wallet.
+fn mine_shielded_coinbase(db: &mut Db) {
+    let address = db.get_new_address(0).unwrap();
+    let address = encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+    db.mine_coinbase(Some(address)).unwrap();
+}
+
+#[test]
+fn exported_notes_can_be_spent_by_another_wallet_with_the_seed() {
+    let mut db = new_db();
+    mine_shielded_coinbase(&mut db);
+    for _ in 0..3 {
+        db.mine_coinbase(None).unwrap();
+    }
+    let WalletNote {
+        id: note_id, note, ..
+    } = db.get_wallet_notes().unwrap().pop().unwrap();
+    let json = db.export_spendable_note(note_id).unwrap();
+    let path = temp_path().with_extension("chain");
+    db.export_chain(&path).unwrap();
+
+    let mut other = new_db();
+    other.import_chain(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert!(other.import_spendable_note(&json).is_err());
+    set_phrase(&other, &mnemonic_phrase(&mut db));
+    other.import_spendable_note(&json).unwrap();
+    let err = other.import_spendable_note(&json).unwrap_err();
+    assert!(err.to_string().contains("already in the wallet"));
+
+    let WalletNote {
+        id: imported_id,
+        note: imported,
+        ..
+    } = other.get_wallet_notes().unwrap().pop().unwrap();
+    assert_eq!(imported, note);
+    submit_note_spend(&mut other, imported_id);
+    other.mine().unwrap();
+    assert_eq!(other.list_spent_notes().unwrap()[0].0, imported_id);
+}
+
+#[test]
+fn rebuilt_witness_resolves_to_the_tip_anchor() {
+    let mut db = new_db();
+    mine_shielded_coinbase(&mut db);
+    mine_shielded_coinbase(&mut db);
+    db.conn
+        .execute(
+            "UPDATE notes SET witness = (SELECT witness FROM notes WHERE id = 2) WHERE id = 1",
+            [],
+        )
+        .unwrap();
+    let (_height, _hash, anchor) = db.tip_summary().unwrap();
+
+    assert_eq!(db.rebuild_witness(1).unwrap(), anchor);
+    let WalletNote { note, witness, .. } = db.get_wallet_notes().unwrap().remove(0);
+    assert_eq!(witness.root().to_bytes(), anchor);
+    let tx = db.conn.transaction().unwrap();
+    let mut leaves = vec![];
+    Db::for_each_block(&tx, |_block_id, block| {
+        leaves.extend(block.extracted_note_commitments());
+        Ok(())
+    })
+    .unwrap();
+    let position = u64::from(witness.witnessed_position()) as usize;
+    assert_eq!(
+        leaves[position],
+        ExtractedNoteCommitment::from(note.commitment())
+    );
+}
+
+#[test]
+fn appending_to_a_full_tree_is_an_error() {
+    use incrementalmerkletree::Hashable;
+
+    let mut db = new_db();
+    mine_shielded_coinbase(&mut db);
+    let last_position: u64 = (1 << 32) - 1;
+    let full_frontier = bincode::serialize(&(
+        last_position,
+        MerkleHashOrchard::empty_leaf(),
+        vec![MerkleHashOrchard::empty_leaf(); 32],
+    ))
+    .unwrap();
+    db.conn
+        .execute(
+            "UPDATE blocks SET frontier = ?1 WHERE id = 1",
+            [full_frontier],
+        )
+        .unwrap();
+
+    let address = db.get_new_address(0).unwrap();
+    let address = encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+    let err = db.mine_coinbase(Some(address)).unwrap_err();
+    assert!(err.to_string().contains("note commitment tree is full"));
+}
+
+#[test]
+fn tree_depth_matches_orchard_anchors() {
+    use incrementalmerkletree::Hashable;
+
+    let empty_root = MerkleHashOrchard::empty_root(Level::from(NOTE_COMMITMENT_TREE_DEPTH));
+    assert_eq!(Anchor::from(empty_root), Anchor::empty_tree());
+}
+
+#[test]
+fn find_missing_notes_reports_deleted_notes() {
+    let mut db = new_db();
+    mine_shielded_coinbase(&mut db);
+    mine_shielded_coinbase(&mut db);
+    assert!(db.find_missing_notes().unwrap().is_empty());
+    db.conn
+        .execute("DELETE FROM notes WHERE id = 2", [])
+        .unwrap();
+    let reward = db.params.block_reward(2);
+    assert_eq!(db.find_missing_notes().unwrap(), vec![(2, reward)]);
+}
+
+#[test]
+fn failed_address_derivation_leaves_no_row_or_gap() {
+    let mut db = new_db();
+    db.get_new_address(0).unwrap();
+    db.conn
+        .execute_batch(
+            "CREATE TEMP TRIGGER fail_address BEFORE INSERT ON addresses
+            BEGIN SELECT RAISE(ABORT, 'injected failure'); END;",
+        )
+        .unwrap();
+    let err = db.get_new_address(0).unwrap_err();
+    assert!(err.to_string().contains("injected failure"));
+    db.conn.execute_batch("DROP TRIGGER fail_address").unwrap();
+
+    let ids = |db: &Db| -> Vec<u32> {
+        let mut statement = db
+            .conn
+            .prepare("SELECT id FROM addresses ORDER BY id")
+            .unwrap();
+        let ids = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        ids
+    };
+    assert_eq!(ids(&db), vec![1]);
+    let address = db.get_new_address(0).unwrap();
+    assert_eq!(ids(&db), vec![1, 2]);
+    let tx = db.conn.transaction().unwrap();
+    let fvk = orchard::keys::FullViewingKey::from(&Db::get_sk(&tx, 0).unwrap());
+    assert_eq!(address, fvk.address_at(2u32, zip32::Scope::External));
+}
+
+#[test]
+fn new_addresses_get_distinct_indexes() {
+    let mut db = new_db();
+    let first = db.get_new_address(0).unwrap();
+    let second = db.get_new_address(0).unwrap();
+    assert_ne!(first, second);
+
+    let tx = db.conn.transaction().unwrap();
+    assert_eq!(
+        Db::list_addresses(&tx, 0).unwrap(),
+        vec![(1, first), (2, second)]
+    );
+    assert_eq!(Db::get_address_at(&tx, 0, 2).unwrap(), Some(second));
+    assert_eq!(Db::get_address_at(&tx, 0, 3).unwrap(), None);
+}
+
+#[test]
+fn mine_selected_leaves_the_rest_pending() {
+    let mut db = new_db();
+    for _ in 0..3 {
+        let utxo_id = conjure(&db, 1000);
+        submit_transfer(&mut db, utxo_id, 900);
+    }
+    let txids: Vec<[u8; 32]> = db
+        .get_pending_transactions()
+        .unwrap()
+        .iter()
+        .map(|(_id, transaction)| transaction.txid())
+        .collect();
+    assert!(db.mine_selected(&[[0; 32]]).is_err());
+    db.mine_selected(&txids[..2]).unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    let mut mined = vec![];
+    Db::for_each_block(&tx, |_block_id, block| {
+        mined.extend(block.transactions.iter().map(|t| t.txid()));
+        Ok(())
+    })
+    .unwrap();
+    drop(tx);
+    assert_eq!(mined, txids[..2]);
+    let pending = db.get_pending_transactions().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].1.txid(), txids[2]);
+}
+
+#[test]
+fn multi_output_notes_follow_commitment_order() {
+    let mut db = new_db();
+    mine_shielded_coinbase(&mut db);
+    let utxo_id = conjure(&db, 10_000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(0, None, 1000, None).unwrap();
+    db.create_change_note(0, 2000, None).unwrap();
+    db.create_note(0, None, 3000, None).unwrap();
+    db.create_change_note(0, 4000, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    let mut leaves = vec![];
+    let mut last_block = None;
+    Db::for_each_block(&tx, |_block_id, block| {
+        leaves.extend(block.extracted_note_commitments());
+        last_block = Some(block);
+        Ok(())
+    })
+    .unwrap();
+    let positions: Vec<usize> = Db::get_notes_with_memos(&tx, &last_block.unwrap())
+        .unwrap()
+        .iter()
+        .map(|(_account, note, _memo)| {
+            let cmx = ExtractedNoteCommitment::from(note.commitment());
+            leaves.iter().position(|leaf| *leaf == cmx).unwrap()
+        })
+        .collect();
+    drop(tx);
+    assert_eq!(positions.len(), 4);
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let (_height, _hash, anchor) = db.tip_summary().unwrap();
+    for WalletNote { note, witness, .. } in db.get_wallet_notes().unwrap().into_iter().skip(1) {
+        let position = u64::from(witness.witnessed_position()) as usize;
+        assert_eq!(
+            leaves[position],
+            ExtractedNoteCommitment::from(note.commitment())
+        );
+        assert_eq!(witness.root().to_bytes(), anchor);
+    }
+}
+
+#[test]
+fn verify_chain_reports_the_first_invalid_block() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    mine_filler_block(&mut db);
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    db.mine().unwrap();
+    db.verify_chain().unwrap();
+
+    db.conn
+        .execute("UPDATE blocks SET fee = 0 WHERE id = 3", [])
+        .unwrap();
+    let err = db.verify_chain().unwrap_err().to_string();
+    assert!(err.contains("block 3"), "{err}");
+    db.conn
+        .execute("UPDATE blocks SET hash = ?1 WHERE id = 2", [[0u8; 32]])
+        .unwrap();
+    let err = db.verify_chain().unwrap_err().to_string();
+    assert!(err.contains("block 2"), "{err}");
+}
+
+#[test]
+fn scanning_with_an_ivk_finds_only_its_outputs() {
+    let mut db = new_db();
+    submit_memo_note(&mut db, 1000, "for the viewer");
+    db.mine().unwrap();
+
+    let ivk = db.incoming_viewing_key(0).unwrap();
+    assert_eq!(
+        new_db()
+            .scan_block_with_ivk(1, &ivk)
+            .unwrap_err()
+            .to_string(),
+        "block 1 does not exist"
+    );
+    assert_eq!(
+        db.scan_block_with_ivk(1, &ivk).unwrap(),
+        vec![(1000, Some("for the viewer".to_string()))]
+    );
+    let other_ivk = new_db().incoming_viewing_key(0).unwrap();
+    assert!(db.scan_block_with_ivk(1, &other_ivk).unwrap().is_empty());
+    assert!(db.scan_block_with_ivk(1, "00").is_err());
+}
+
+#[test]
+fn bench_mines_the_requested_workload() {
+    let mut db = new_db();
+    let report = db.bench_mine(2, 2).unwrap();
+    assert_eq!((report.blocks, report.notes), (2, 4));
+    assert_eq!(db.status().unwrap().height, 2);
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 4);
+}
+
+#[test]
+fn notes_become_spendable_at_the_anchor_depth() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    for _ in 1..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+        assert_eq!(db.get_spendable_shielded_value(None).unwrap(), 0);
+    }
+    mine_filler_block(&mut db);
+    assert_eq!(db.get_spendable_shielded_value(None).unwrap(), 1000);
+
+    db.spend_note(1).unwrap();
+    assert_eq!(db.get_spendable_shielded_value(None).unwrap(), 0);
+}
+
+#[test]
+fn default_path_is_absolute_and_stable() {
+    let path = Db::default_path().unwrap();
+    assert!(path.is_absolute());
+    assert!(path.ends_with(Path::new("orchard_sandbox").join(DB_FILE_NAME)));
+    assert!(path.parent().unwrap().is_dir());
+    assert_eq!(Db::default_path().unwrap(), path);
+}
+
+#[test]
+fn preview_change_subtracts_outputs_and_fee() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let utxo_id = conjure(&db, 500);
+    db.spend_utxo(utxo_id).unwrap();
+    db.spend_note(1).unwrap();
+    db.create_utxo(700).unwrap();
+    db.create_note(0, None, 300, None).unwrap();
+    assert_eq!(db.preview_change(100).unwrap(), 400);
+    assert_eq!(db.preview_change(600).unwrap(), -100);
+}
+
+#[test]
+fn staged_note_spends_are_submitted_and_mined() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    let nullifier = db.note_nullifier(note_id).unwrap();
+    db.spend_note(note_id).unwrap();
+    db.create_note(0, None, 900, None).unwrap();
+    assert!(db.submit_transaction().unwrap().is_empty());
+    assert!(db.pending_transaction_state().unwrap().is_empty());
+    db.mine().unwrap();
+
+    assert!(db.is_nullifier_spent(&nullifier).unwrap());
+    assert_eq!(db.list_spent_notes().unwrap(), vec![(note_id, 1000, 5)]);
+}
+
+#[test]
+fn values_above_i64_max_are_rejected() {
+    let mut db = new_db();
+    let too_large = i64::MAX as u64 + 1;
+    assert!(db.conjure_utxo(too_large).is_err());
+
+    // As written by a wrapping cast.
+    db.conn
+        .execute("INSERT INTO utxos (value) VALUES (?1)", [too_large as i64])
+        .unwrap();
+    let utxo_id = db.conn.last_insert_rowid() as u32;
+    let tx = db.conn.transaction().unwrap();
+    let err = Db::get_utxo_value(&tx, utxo_id).unwrap_err();
+    assert!(err.to_string().contains("out of range"), "{err}");
+    drop(tx);
+    assert!(db.get_total_transparent_value().is_err());
+
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(1000).unwrap();
+    let err = db.preview_change(0).unwrap_err();
+    assert!(err.to_string().contains("out of range"), "{err}");
+    db.submit_transaction().unwrap();
+    let reason = mine_rejected(&mut db);
+    assert!(reason.contains("out of range"), "{reason}");
+}
+
+#[test]
+fn negative_stored_values_are_rejected() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    db.conn
+        .execute("INSERT INTO utxos (value) VALUES (-1)", [])
+        .unwrap();
+    let utxo_id = db.conn.last_insert_rowid() as u32;
+    db.conn.execute("UPDATE notes SET value = -1", []).unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    assert!(Db::get_utxo_value(&tx, utxo_id).is_err());
+    drop(tx);
+    assert!(db.get_total_transparent_value().is_err());
+    assert!(db.get_total_shielded_value(None).is_err());
+}
+
+#[test]
+fn in_memory_databases_are_independent() {
+    let mut db = Db::new_in_memory().unwrap();
+    db.mine_coinbase(None).unwrap();
+    assert_eq!(db.status().unwrap().height, 1);
+    let mut other = Db::new_in_memory().unwrap();
+    assert_eq!(other.status().unwrap().height, 0);
+    assert_ne!(mnemonic_phrase(&mut db), mnemonic_phrase(&mut other));
+}
+
+#[test]
+fn locked_utxos_cannot_be_spent_until_unlocked() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    db.lock_utxo(utxo_id).unwrap();
+    assert_eq!(db.get_utxos().unwrap(), vec![(utxo_id, 1000, true)]);
+    let err = db.spend_utxo(utxo_id).unwrap_err();
+    assert!(err.to_string().contains("locked"), "{err}");
+
+    db.unlock_utxo(utxo_id).unwrap();
+    assert_eq!(db.get_utxos().unwrap(), vec![(utxo_id, 1000, false)]);
+    db.spend_utxo(utxo_id).unwrap();
+    assert!(db.lock_utxo(utxo_id + 1).is_err());
+}
+
+#[test]
+fn frontier_ommers_are_the_left_siblings_of_the_tip_leaf() {
+    let mut db = new_db();
+    assert!(db.frontier_ommers().unwrap().is_empty());
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    let mut leaves = vec![];
+    Db::for_each_block(&tx, |_block_id, block| {
+        leaves.extend(block.extracted_note_commitments());
+        Ok(())
+    })
+    .unwrap();
+    drop(tx);
+    // One output padded to the minimum of two actions.
+    assert_eq!(leaves.len(), 2);
+    assert_eq!(db.frontier_ommers().unwrap(), vec![leaves[0].to_bytes()]);
+}
+
+#[test]
+fn select_utxos_prefers_an_exact_match_then_the_largest() {
+    let mut db = new_db();
+    let small = conjure(&db, 100);
+    let large = conjure(&db, 500);
+    let exact = conjure(&db, 300);
+    let locked = conjure(&db, 1000);
+    db.lock_utxo(locked).unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    assert_eq!(
+        Db::select_utxos(&tx, &db.params, 300).unwrap(),
+        vec![(exact, 300)]
+    );
+    assert_eq!(
+        Db::select_utxos(&tx, &db.params, 700).unwrap(),
+        vec![(large, 500), (exact, 300)]
+    );
+    assert!(Db::select_utxos(&tx, &db.params, 1000).is_err());
+    drop(tx);
+
+    assert_eq!(
+        db.spend_value(550).unwrap(),
+        vec![(large, 500), (exact, 300)]
+    );
+    assert_eq!(
+        db.pending_transaction_state().unwrap().inputs,
+        vec![large, exact]
+    );
+    assert_eq!(db.spend_value(100).unwrap(), vec![(small, 100)]);
+}
+
+#[test]
+fn resubmitting_a_mempool_transaction_is_rejected() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(900).unwrap();
+    let err = db.submit_transaction().unwrap_err();
+    assert!(err.to_string().contains("already in mempool"), "{err}");
+    assert_eq!(db.get_pending_transactions().unwrap().len(), 1);
+    assert_eq!(db.preview_change(0).unwrap(), 100);
+}
+
+#[test]
+fn transparent_change_returns_to_a_new_utxo() {
+    let mut db = new_db();
+    let default_fee = db.params.default_fee();
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(600).unwrap();
+    assert!(db.submit_transaction_with_change(default_fee - 1).is_err());
+    let err = db.submit_transaction_with_change(500).unwrap_err();
+    assert!(err.to_string().contains("insufficient funds"), "{err}");
+
+    let (change, pending_utxos) = db.submit_transaction_with_change(default_fee).unwrap();
+    assert_eq!(change, Some(400 - default_fee));
+    assert_eq!(pending_utxos.len(), 2);
+    db.mine().unwrap();
+    let mut values: Vec<u64> = db
+        .get_utxos()
+        .unwrap()
+        .into_iter()
+        .map(|(_id, value, _locked)| value)
+        .collect();
+    values.sort();
+    assert_eq!(values, vec![400 - default_fee, 600]);
+
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_utxo(1000 - default_fee).unwrap();
+    assert_eq!(
+        db.submit_transaction_with_change(default_fee).unwrap().0,
+        None
+    );
+}
+
+#[test]
+fn peek_next_block_matches_the_mined_block_without_storing_it() {
+    let mut db = new_db();
+    assert!(db.peek_next_block(1_700_000_000).unwrap().is_none());
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+
+    let preview = db.peek_next_block(1_700_000_000).unwrap().unwrap();
+    assert_eq!((preview.height, preview.fee), (1, 100));
+    assert_eq!(db.status().unwrap().height, 0);
+    assert_eq!(db.get_pending_transactions().unwrap().len(), 1);
+
+    db.mine_at(1_700_000_000, false).unwrap();
+    let tx = db.conn.transaction().unwrap();
+    assert_eq!(Db::get_block_hash(&tx, 1).unwrap(), preview.hash);
+}
+
+#[test]
+fn staged_memos_are_stored_with_the_received_note() {
+    let mut db = new_db();
+    assert!(memo_from_text(&"x".repeat(513)).is_err());
+    submit_memo_note(&mut db, 1000, "thanks for lunch");
+    db.mine().unwrap();
+    let memo: Vec<u8> = db
+        .conn
+        .query_row("SELECT memo FROM notes WHERE value = 1000", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let memo: [u8; 512] = memo.try_into().unwrap();
+    assert_eq!(memo_text(&memo).as_deref(), Some("thanks for lunch"));
+}
+
+#[test]
+fn wallet_notes_carry_their_memos() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    submit_memo_note(&mut db, 2000, "rent");
+    db.mine().unwrap();
+    let memos: Vec<(u64, Option<String>)> = db
+        .get_wallet_notes()
+        .unwrap()
+        .iter()
+        .map(|note| {
+            let memo = note.memo.as_ref().map(memo_display);
+            (note.note.value().inner(), memo)
+        })
+        .collect();
+    assert_eq!(memos, vec![(1000, None), (2000, Some("rent".to_owned()))]);
+
+    let mut memo = [0u8; 512];
+    memo[..3].copy_from_slice(&[0xff, 0x00, 0x01]);
+    assert_eq!(memo_display(&memo), "ff0001");
+    memo[0] = 0xf6;
+    assert!(!is_empty_memo(&memo));
+    assert!(is_empty_memo(&[0u8; 512]));
+}
+
+#[test]
+fn malformed_frontiers_and_witnesses_are_errors() {
+    use incrementalmerkletree::Hashable;
+
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+
+    db.conn
+        .execute("UPDATE notes SET witness = ?1", [[1u8; 3]])
+        .unwrap();
+    let err = db.get_wallet_notes().unwrap_err();
+    assert!(err.to_string().contains("malformed witness"), "{err}");
+
+    // Position 5 has two ommers in a valid frontier.
+    let frontier = bincode::serialize(&(
+        5u64,
+        MerkleHashOrchard::empty_leaf(),
+        Vec::<MerkleHashOrchard>::new(),
+    ))
+    .unwrap();
+    db.conn
+        .execute("UPDATE blocks SET frontier = ?1", [frontier])
+        .unwrap();
+    let err = db.frontier_ommers().unwrap_err();
+    assert!(err.to_string().contains("malformed"), "{err}");
+    assert!(db.mine_coinbase(None).is_err());
+}
+
+#[test]
+fn malformed_note_parts_are_errors() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+
+    db.conn
+        .execute("UPDATE notes SET rho = ?1", [[0u8; 3]])
+        .unwrap();
+    let err = db.get_wallet_notes().unwrap_err();
+    let expected = format!("note {note_id} has a rho of the wrong length");
+    assert!(err.to_string().contains(&expected), "{err}");
+
+    // Not the encoding of a Pallas base field element.
+    db.conn
+        .execute("UPDATE notes SET rho = ?1", [[0xffu8; 32]])
+        .unwrap();
+    let err = db.note_nullifier(note_id).unwrap_err();
+    let expected = format!("note {note_id} has a malformed rho");
+    assert!(err.to_string().contains(&expected), "{err}");
+}
+
+#[test]
+fn notes_are_spendable_at_the_configured_anchor_depth() {
+    let mut db = new_db();
+    db.params.anchor_depth = 1;
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    assert_eq!(db.get_spendable_shielded_value(None).unwrap(), 0);
+    mine_filler_block(&mut db);
+    assert_eq!(db.get_spendable_shielded_value(None).unwrap(), 1000);
+
+    db.spend_note(1).unwrap();
+    db.create_note(0, None, 900, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+    assert_eq!(db.list_spent_notes().unwrap(), vec![(1, 1000, 3)]);
+}
+
+#[test]
+fn disconnect_tip_undoes_the_block() {
+    let mut db = new_db();
+    assert!(db.disconnect_tip().is_err());
+    db.params.anchor_depth = 0;
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let nullifiers = db.all_nullifiers().unwrap().len();
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+
+    db.spend_note(1).unwrap();
+    db.create_note(0, None, 900, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+    assert!(db.all_nullifiers().unwrap().len() > nullifiers);
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 2);
+    assert_eq!(db.list_spent_notes().unwrap().len(), 1);
+
+    assert_eq!(db.disconnect_tip().unwrap(), 2);
+    assert_eq!(db.all_nullifiers().unwrap().len(), nullifiers);
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+    assert!(db.list_spent_notes().unwrap().is_empty());
+    db.verify_chain().unwrap();
+}
+
+#[test]
+fn coinbase_rewards_default_to_the_miner_address() {
+    let mut db = new_db();
+    assert!(db.set_miner_address(Some("not an address")).is_err());
+    let address = db.get_new_address(0).unwrap();
+    let address = encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+    db.set_miner_address(Some(&address)).unwrap();
+    assert_eq!(db.get_miner_address().unwrap(), Some(address));
+
+    db.mine_coinbase(None).unwrap();
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+    assert!(db.get_utxos().unwrap().is_empty());
+
+    db.set_miner_address(None).unwrap();
+    assert_eq!(db.get_miner_address().unwrap(), None);
+    db.mine_coinbase(None).unwrap();
+    assert_eq!(db.get_utxos().unwrap().len(), 1);
+}
+
+#[test]
+fn coinbase_must_match_issuance() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    let bytes = hex::decode(db.export_block(1).unwrap()).unwrap();
+    let (previous_hash, timestamp, issuance, block): ([u8; 32], i64, u64, Block) =
+        bincode::deserialize(&bytes).unwrap();
+    let inflated = bincode::serialize(&(previous_hash, timestamp, issuance + 1, block)).unwrap();
+    let mut other = new_db();
+    assert!(other.import_block(&hex::encode(inflated)).is_err());
+    other.import_block(&hex::encode(bytes)).unwrap();
+}
+
+#[test]
+fn imported_blocks_extend_the_tip() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    db.mine_coinbase(None).unwrap();
+    let mut other = new_db();
+    let second = db.export_block(2).unwrap();
+    assert!(other.import_block(&second).is_err());
+    other.import_block(&db.export_block(1).unwrap()).unwrap();
+    let (height, hash) = other.import_block(&second).unwrap();
+    let (tip_height, tip_hash, _anchor) = db.tip_summary().unwrap();
+    assert_eq!((height, hash), (tip_height, tip_hash));
+    assert!(other.import_block(&second).is_err());
+}
+
+#[test]
+fn imported_blocks_with_malformed_actions_are_rejected() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let mut other = new_db();
+    other.import_block(&db.export_block(1).unwrap()).unwrap();
+
+    let bytes = hex::decode(db.export_block(2).unwrap()).unwrap();
+    let (previous_hash, timestamp, issuance, mut block): ([u8; 32], i64, u64, Block) =
+        bincode::deserialize(&bytes).unwrap();
+    block.transactions[0].actions[0].enc_ciphertext.pop();
+    let malformed = bincode::serialize(&(previous_hash, timestamp, issuance, block)).unwrap();
+    let err = other.import_block(&hex::encode(malformed)).unwrap_err();
+    assert!(err.to_string().contains("malformed"), "{err}");
+}
+
+#[test]
+fn nullifiers_record_their_block() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    submit_shielding(&mut db, 2000);
+    db.mine().unwrap();
+    let per_block: Vec<(u32, u32)> = db
+        .conn
+        .prepare("SELECT block_id, COUNT(*) FROM nullifiers GROUP BY block_id ORDER BY block_id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(per_block, vec![(1, 2), (2, 2)]);
+
+    let tx = db.conn.transaction().unwrap();
+    assert_eq!(Db::remove_block_nullifiers(&tx, 2).unwrap(), 2);
+    assert_eq!(Db::remove_block_nullifiers(&tx, 2).unwrap(), 0);
+    tx.commit().unwrap();
+    let block_ids: Vec<Option<u32>> = db
+        .all_nullifiers()
+        .unwrap()
+        .into_iter()
+        .map(|(_nullifier, block_id)| block_id)
+        .collect();
+    assert_eq!(block_ids, vec![Some(1), Some(1)]);
+}
+
+#[test]
+fn prune_spent_notes_keeps_recently_spent_notes() {
+    let mut db = new_db();
+    db.params.anchor_depth = 0;
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    db.spend_note(1).unwrap();
+    db.create_note(0, None, 900, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+    mine_filler_block(&mut db);
+
+    assert_eq!(db.prune_spent_notes(10).unwrap(), 0);
+    assert_eq!(db.prune_spent_notes(1).unwrap(), 1);
+    assert!(db.list_spent_notes().unwrap().is_empty());
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+    assert!(db.find_missing_notes().unwrap().is_empty());
+}
+
+#[test]
+fn accounts_hold_and_spend_their_own_notes() {
+    let mut db = new_db();
+    db.params.anchor_depth = 0;
+    assert!(db.get_new_address(1).is_err());
+    assert_eq!(db.new_account().unwrap(), 1);
+    assert_ne!(
+        db.incoming_viewing_key(0).unwrap(),
+        db.incoming_viewing_key(1).unwrap()
+    );
+
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(1, None, 1000, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+    assert_eq!(db.get_total_shielded_value(Some(0)).unwrap(), 0);
+    assert_eq!(db.get_total_shielded_value(Some(1)).unwrap(), 1000);
+
+    db.spend_note(1).unwrap();
+    db.create_change_note(1, 900, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+    assert_eq!(db.get_total_shielded_value(Some(1)).unwrap(), 900);
+    assert_eq!(db.get_total_shielded_value(None).unwrap(), 900);
+}
+
+#[test]
+fn mining_refuses_an_inconsistent_frontier() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    db.conn
+        .execute("UPDATE blocks SET frontier = NULL WHERE id = 1", [])
+        .unwrap();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    let err = db.mine().unwrap_err();
+    assert!(err.to_string().contains("inconsistent"), "{err}");
+    let (_id, transaction) = db.get_pending_transactions().unwrap().pop().unwrap();
+    let err = db.mine_selected(&[transaction.txid()]).unwrap_err();
+    assert!(err.to_string().contains("inconsistent"), "{err}");
+    assert_eq!(db.get_pending_transactions().unwrap().len(), 1);
+}
+
+#[test]
+fn restoring_a_mnemonic_reproduces_its_addresses() {
+    let mut db = new_db();
+    let phrase = mnemonic_phrase(&mut db);
+    let address = db.get_new_address(0).unwrap();
+
+    let mut restored = new_db();
+    restored.get_new_address(0).unwrap();
+    assert!(restored
+        .restore_from_mnemonic("not a mnemonic", false)
+        .is_err());
+    restored.restore_from_mnemonic(&phrase, false).unwrap();
+    assert_eq!(mnemonic_phrase(&mut restored), phrase);
+    assert_eq!(restored.get_new_address(0).unwrap(), address);
+
+    submit_shielding(&mut restored, 1000);
+    restored.mine().unwrap();
+    let other_phrase = mnemonic_phrase(&mut new_db());
+    assert!(restored
+        .restore_from_mnemonic(&other_phrase, false)
+        .is_err());
+    restored.restore_from_mnemonic(&other_phrase, true).unwrap();
+    assert!(restored.get_wallet_notes().unwrap().is_empty());
+}
+
+#[test]
+fn mempool_dependencies_list_unmined_parents() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    let parent_utxo = submit_transfer(&mut db, utxo_id, 900);
+    submit_transfer(&mut db, parent_utxo, 800);
+    let other_utxo = conjure(&db, 1000);
+    submit_transfer(&mut db, other_utxo, 900);
+
+    let dependencies = db.mempool_dependencies().unwrap();
+    assert_eq!(dependencies.len(), 3);
+    assert!(dependencies[0].parents.is_empty());
+    assert_eq!(dependencies[1].parents, vec![dependencies[0].txid]);
+    assert!(dependencies[2].parents.is_empty());
+}
+
+#[test]
+fn stored_addresses_are_verified_against_the_seed() {
+    let mut db = new_db();
+    db.get_new_address(0).unwrap();
+    db.get_new_address(0).unwrap();
+    db.create_change_note(0, 1000, None).unwrap();
+    assert_eq!(db.verify_addresses().unwrap(), 3);
+
+    set_phrase(&db, FIXED_PHRASE);
+    let err = db.verify_addresses().unwrap_err().to_string();
+    assert!(err.contains("address 1 of account 0"), "{err}");
+    assert!(err.contains("change address"), "{err}");
+}
+
+#[test]
+fn built_transactions_leave_the_staged_transaction_alone() {
+    let mut db = new_db();
+    db.params.anchor_depth = 0;
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    db.create_utxo(5).unwrap();
+
+    let utxo_id = conjure(&db, 2000);
+    let recipient = db.get_new_address(0).unwrap();
+    let transaction = {
+        let tx = db.conn.transaction().unwrap();
+        let mut builder = crate::builder::TransactionBuilder::new(&tx, &db.params).unwrap();
+        builder.add_transparent_input(utxo_id);
+        builder.add_note_spend(&tx, &db.params, 1).unwrap();
+        builder.add_transparent_output(1500);
+        builder.add_shielded_output(recipient, 1400, None).unwrap();
+        builder.build(&db.keys).unwrap()
+    };
+    assert_eq!(db.submit_built(&transaction).unwrap().len(), 1);
+    db.mine().unwrap();
+
+    assert_eq!(db.list_spent_notes().unwrap().len(), 1);
+    assert_eq!(db.get_total_shielded_value(None).unwrap(), 1400);
+    let state = db.pending_transaction_state().unwrap();
+    assert_eq!(state.outputs.len(), 1);
+    assert_eq!(state.outputs[0].value, 5);
+}
+
+#[test]
+fn tampered_value_commitments_fail_the_signature_check() {
+    // Signatures are checked even without proof verification.
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    let recipient = db.get_new_address(0).unwrap();
+    let transaction = {
+        let tx = db.conn.transaction().unwrap();
+        let mut builder = crate::builder::TransactionBuilder::new(&tx, &db.params).unwrap();
+        builder.add_transparent_input(utxo_id);
+        builder.add_shielded_output(recipient, 900, None).unwrap();
+        builder.build(&db.keys).unwrap()
+    };
+    assert!(transaction.authorization.is_some());
+
+    let mut tampered = transaction.clone();
+    tampered.actions[0].cv_net = tampered.actions[1].cv_net;
+    db.submit_built(&tampered).unwrap();
+    let reason = mine_rejected(&mut db);
+    assert!(reason.contains("signature"), "{reason}");
+
+    // Stripping the signatures doesn't get around them.
+    let mut stripped = new_db();
+    conjure(&stripped, 1000);
+    tampered.authorization = None;
+    stripped.submit_built(&tampered).unwrap();
+    let reason = mine_rejected(&mut stripped);
+    assert!(reason.contains("unauthorized"), "{reason}");
+
+    let mut other = new_db();
+    conjure(&other, 1000);
+    other.submit_built(&transaction).unwrap();
+    other.mine().unwrap();
+    assert_eq!(other.status().unwrap().height, 1);
+}
+
+#[test]
+fn check_integrity_flags_fees_and_unbalanced_pools() {
+    let mut db = new_db();
+    db.mine_coinbase(None).unwrap();
+    assert!(db.check_integrity().unwrap().is_empty());
+
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    db.mine().unwrap();
+    let issues = db.check_integrity().unwrap();
+    assert_eq!(issues.len(), 1);
+    assert!(issues[0].contains("0.00000100 paid as fees"), "{issues:?}");
+
+    db.conn
+        .execute(
+            "UPDATE utxos SET value = value + 1 WHERE id = ?1",
+            [utxo_id],
+        )
+        .unwrap();
+    let issues = db.check_integrity().unwrap();
+    assert_eq!(issues.len(), 2);
+    assert!(issues[0].contains("supply doesn't balance"), "{issues:?}");
+}
+
+#[test]
+fn my_outputs_lists_outputs_paying_any_account() {
+    let mut db = new_db();
+    db.new_account().unwrap();
+    submit_memo_note(&mut db, 1000, "first");
+    let utxo_id = conjure(&db, 2000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(1, None, 2000, None).unwrap();
+    db.submit_transaction().unwrap();
+    db.mine().unwrap();
+
+    let mut outputs = db.my_outputs_in_block(1).unwrap();
+    outputs.sort();
+    assert_eq!(
+        outputs,
+        vec![(1000, Some("first".to_string())), (2000, None)]
+    );
+    assert!(db.my_outputs_in_block(2).is_err());
+}
+
+#[test]
+fn lock_errors_name_the_other_process() {
+    let busy =
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None);
+    assert!(lock_error(busy)
+        .to_string()
+        .contains("locked by another process"));
+    let other = rusqlite::Error::QueryReturnedNoRows;
+    assert!(!lock_error(other)
+        .to_string()
+        .contains("locked by another process"));
+}
+
+#[test]
+fn init_fresh_refuses_existing_files() {
+    let path = temp_path();
+    let mut db = Db::init_fresh(&path).unwrap();
+    let phrase = mnemonic_phrase(&mut db);
+    drop(db);
+    let err = Db::init_fresh(&path).err().unwrap();
+    assert!(err.to_string().contains("already exists"), "{err}");
+    assert_eq!(mnemonic_phrase(&mut Db::new_at(&path).unwrap()), phrase);
+}
+
+#[test]
+fn balance_at_counts_values_unspent_at_each_height() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    let change_id = submit_transfer(&mut db, utxo_id, 900);
+    db.mine().unwrap();
+    submit_shielding(&mut db, 500);
+    db.mine().unwrap();
+    submit_transfer(&mut db, change_id, 800);
+    db.mine().unwrap();
+
+    // The utxo shielded in block 2 was conjured after block 1, so it doesn't count there.
+    assert_eq!(db.balance_at(1).unwrap(), (900, 0));
+    assert_eq!(db.balance_at(2).unwrap(), (900, 500));
+    assert_eq!(db.balance_at(3).unwrap(), (800, 500));
+    let err = db.balance_at(4).unwrap_err();
+    assert!(err.to_string().contains("above the tip"), "{err}");
+}
+
+#[test]
+fn dry_spend_requires_the_note_to_be_deep_enough() {
+    let mut db = new_db();
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    let err = db.dry_spend(note_id, 500, 100).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("isn't in the chain up to the anchor block"),
+        "{err}"
+    );
+
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    db.dry_spend(note_id, 500, 100).unwrap();
+    db.dry_spend(note_id, 900, 100).unwrap();
+    let err = db.dry_spend(note_id, 950, 100).unwrap_err();
+    assert!(err.to_string().contains("less than amount"), "{err}");
+
+    let nullifier = db.note_nullifier(note_id).unwrap();
+    assert!(!db.is_nullifier_spent(&nullifier).unwrap());
+    assert!(db.pending_transaction_state().unwrap().is_empty());
+    assert_eq!(db.get_wallet_notes().unwrap().len(), 1);
+}
+
+#[test]
+fn minimum_transaction_fee_is_enforced() {
+    let mut db = new_db();
+    db.params.min_fee = 100;
+    let at_minimum = conjure(&db, 1000);
+    submit_transfer(&mut db, at_minimum, 900);
+    db.mine().unwrap();
+    assert_eq!(db.supply_audit().unwrap().fees, 100);
+
+    let below_minimum = conjure(&db, 1000);
+    submit_transfer(&mut db, below_minimum, 901);
+    let reason = mine_rejected(&mut db);
+    assert!(reason.contains("below the minimum fee 100"), "{reason}");
+    assert_eq!(db.supply_audit().unwrap().fees, 100);
+}
+
+#[test]
+fn fee_estimate_charges_logical_actions_above_the_grace() {
+    let mut db = new_db();
+    let marginal_fee = db.params.marginal_fee;
+    assert_eq!(db.estimate_fee(0, 0, 0), 2 * marginal_fee);
+    assert_eq!(db.estimate_fee(1, 1, 0), 2 * marginal_fee);
+    assert_eq!(db.estimate_fee(3, 1, 0), 3 * marginal_fee);
+    assert_eq!(db.estimate_fee(1, 2, 2), 4 * marginal_fee);
+
+    // A single shielded output is padded to two actions.
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    db.create_note(0, None, 900, None).unwrap();
+    assert_eq!(db.estimate_pending_fee().unwrap(), 3 * marginal_fee);
+}
+
+#[test]
+fn diagnose_spend_matches_the_anchor_once_the_note_is_mature() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    let diagnosis = db.diagnose_spend(note_id).unwrap();
+    assert_eq!(diagnosis.position, 0);
+    assert_eq!(diagnosis.created_block, Some(1));
+    assert!(!diagnosis.is_mature());
+    assert!(!diagnosis.path_matches_anchor());
+
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    let diagnosis = db.diagnose_spend(note_id).unwrap();
+    assert_eq!(diagnosis.anchor_height, Some(1));
+    assert!(diagnosis.is_mature());
+    assert!(diagnosis.path_matches_anchor());
+}
+
+#[test]
+fn chain_summary_and_blocks_show_mined_contents() {
+    let mut db = new_db();
+    assert_eq!(db.get_block_count().unwrap(), 0);
+    assert!(db.get_block(1).unwrap().is_none());
+
+    db.mine_coinbase(None).unwrap();
+    let first = conjure(&db, 1000);
+    let second = conjure(&db, 1000);
+    submit_transfer(&mut db, first, 900);
+    submit_transfer(&mut db, second, 950);
+    db.mine().unwrap();
+
+    assert_eq!(db.get_block_count().unwrap(), 2);
+    let (fee, block) = db.get_block(2).unwrap().unwrap();
+    assert_eq!(fee, 150);
+    assert_eq!(block.transactions.len(), 2);
+    assert!(block
+        .transactions
+        .iter()
+        .all(|transaction| transaction.inputs.len() == 1 && transaction.outputs.len() == 1));
+    let summary = db.chain_summary().unwrap();
+    assert_eq!(summary.height, 2);
+    assert_eq!(summary.total_fees, 150);
+    assert_eq!(summary.transactions, 3);
+}
+
+#[test]
+fn wal_autocheckpoint_is_applied() {
+    let path = temp_path();
+    let db = Db::new_at(&path).unwrap();
+    let pages = |db: &Db| -> u32 {
+        db.conn
+            .query_row("PRAGMA wal_autocheckpoint", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(pages(&db), 1000);
+    db.set_wal_autocheckpoint(16).unwrap();
+    assert_eq!(pages(&db), 16);
+    db.set_wal_autocheckpoint(0).unwrap();
+    assert_eq!(pages(&db), 0);
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn transactions_are_classified_by_kind() {
+    use crate::types::TransactionKind;
+
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    let utxo_id = conjure(&db, 1000);
+    let recipient = db.get_new_address(0).unwrap();
+
+    let tx = db.conn.transaction().unwrap();
+    let kind = |builder: TransactionBuilder| builder.build(&db.keys).unwrap().kind();
+    let mut transparent = TransactionBuilder::new(&tx, &db.params).unwrap();
+    transparent.add_transparent_input(utxo_id);
+    transparent.add_transparent_output(900);
+    assert_eq!(kind(transparent), TransactionKind::Transparent);
+
+    let mut shield = TransactionBuilder::new(&tx, &db.params).unwrap();
+    shield.add_transparent_input(utxo_id);
+    shield.add_shielded_output(recipient, 900, None).unwrap();
+    assert_eq!(kind(shield), TransactionKind::Shield);
+
+    let mut deshield = TransactionBuilder::new(&tx, &db.params).unwrap();
+    deshield.add_note_spend(&tx, &db.params, note_id).unwrap();
+    deshield.add_transparent_output(900);
+    assert_eq!(kind(deshield), TransactionKind::Deshield);
+
+    let mut shielded = TransactionBuilder::new(&tx, &db.params).unwrap();
+    shielded.add_note_spend(&tx, &db.params, note_id).unwrap();
+    shielded.add_shielded_output(recipient, 900, None).unwrap();
+    assert_eq!(kind(shielded), TransactionKind::Shielded);
+}
+
+#[test]
+fn verify_chain_accepts_blocks_mined_under_a_lower_minimum_fee() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 1000);
+    db.mine().unwrap();
+    db.params.min_fee = 100;
+    db.verify_chain().unwrap();
+
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 1000);
+    let reason = mine_rejected(&mut db);
+    assert!(reason.contains("below the minimum fee 100"), "{reason}");
+}
+
+#[test]
+fn default_fee_is_raised_to_the_minimum_fee() {
+    let mut db = new_db();
+    assert_eq!(db.params.default_fee(), db.params.default_change_fee);
+    db.params.min_fee = db.params.default_change_fee + 1;
+    assert_eq!(db.params.default_fee(), db.params.min_fee);
+
+    let utxo_id = conjure(&db, 1000);
+    db.spend_utxo(utxo_id).unwrap();
+    let err = db
+        .submit_transaction_with_change(db.params.default_change_fee)
+        .unwrap_err();
+    assert!(err.to_string().contains("below the minimum fee"), "{err}");
+    let (change, _pending_utxos) = db
+        .submit_transaction_with_change(db.params.min_fee)
+        .unwrap();
+    assert_eq!(change, Some(1000 - db.params.min_fee));
+}
+
+#[test]
+fn mining_skips_invalid_transactions() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    submit_transfer(&mut db, utxo_id, 900);
+    let double_spent = submit_transfer(&mut db, utxo_id, 800);
+    submit_transfer(&mut db, double_spent, 700);
+    let txids: Vec<[u8; 32]> = db
+        .get_pending_transactions()
+        .unwrap()
+        .iter()
+        .map(|(_id, transaction)| transaction.txid())
+        .collect();
+
+    let result = db.mine_at(current_timestamp().unwrap(), false).unwrap();
+    assert_eq!(result.block_id, Some(1));
+    assert_eq!(result.mined, txids[..1]);
+    let rejected: Vec<[u8; 32]> = result
+        .rejected
+        .iter()
+        .map(|rejected| rejected.txid)
+        .collect();
+    assert_eq!(rejected, txids[1..]);
+    assert_eq!(db.get_pending_transactions().unwrap().len(), 2);
+
+    let result = db.mine_at(current_timestamp().unwrap(), true).unwrap();
+    assert_eq!(result.block_id, None);
+    assert_eq!(result.rejected.len(), 2);
+    assert!(db.get_pending_transactions().unwrap().is_empty());
+}
+
+#[test]
+fn a_duplicate_nullifier_doesnt_block_the_other_transaction() {
+    let mut db = new_db();
+    submit_shielding(&mut db, 1000);
+    db.mine().unwrap();
+    for _ in 0..db.params.anchor_depth {
+        mine_filler_block(&mut db);
+    }
+    let note_id = db.get_wallet_notes().unwrap().pop().unwrap().id;
+    submit_note_spend(&mut db, note_id);
+    submit_note_spend(&mut db, note_id);
+    let txids: Vec<[u8; 32]> = db
+        .get_pending_transactions()
+        .unwrap()
+        .iter()
+        .map(|(_id, transaction)| transaction.txid())
+        .collect();
+
+    let result = db.mine().unwrap();
+    assert_eq!(result.mined, txids[..1]);
+    assert_eq!(result.rejected.len(), 1);
+    assert_eq!(result.rejected[0].txid, txids[1]);
+    assert!(
+        result.rejected[0].reason.contains("already spent"),
+        "{}",
+        result.rejected[0].reason
+    );
+    let nullifier = db.note_nullifier(note_id).unwrap();
+    assert!(db.is_nullifier_spent(&nullifier).unwrap());
+}
+
+#[test]
+fn tree_size_counts_every_note_commitment() {
+    let mut db = new_db();
+    assert_eq!(db.tree_size().unwrap(), 0);
+    for value in [1000, 2000, 3000] {
+        submit_shielding(&mut db, value);
+        db.mine().unwrap();
+    }
+    mine_filler_block(&mut db);
+
+    let actions: u64 = (1..=db.get_block_count().unwrap())
+        .map(|block_id| {
+            let (_fee, block) = db.get_block(block_id).unwrap().unwrap();
+            block
+                .transactions
+                .iter()
+                .map(|transaction| transaction.actions.len() as u64)
+                .sum::<u64>()
+        })
+        .sum();
+    // Each single output bundle is padded with a dummy action, whose commitment counts too.
+    assert_eq!(actions, 6);
+    assert_eq!(db.tree_size().unwrap(), actions);
+    assert_eq!(db.status().unwrap().tree_size, actions);
+}
+
+#[test]
+fn disconnect_tip_drops_mempool_transactions_spending_its_utxos() {
+    let mut db = new_db();
+    let utxo_id = conjure(&db, 1000);
+    let mined_utxo = submit_transfer(&mut db, utxo_id, 900);
+    db.mine().unwrap();
+    let pending_utxo = submit_transfer(&mut db, mined_utxo, 800);
+    submit_transfer(&mut db, pending_utxo, 700);
+    let unrelated = conjure(&db, 1000);
+    submit_transfer(&mut db, unrelated, 900);
+    let (_id, unrelated) = db.get_pending_transactions().unwrap().pop().unwrap();
+
+    db.disconnect_tip().unwrap();
+    let pending = db.get_pending_transactions().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].1.txid(), unrelated.txid());
+    assert!(db.utxo_info(mined_utxo).is_err());
+    assert!(db.utxo_info(pending_utxo).is_err());
+    assert_eq!(db.utxo_info(utxo_id).unwrap().spent_block, None);
+}
+
+#[test]
+fn disconnect_tip_rolls_back_advanced_witnesses() {
+    let mut db = new_db();
+    for value in [1000, 2000, 3000] {
+        submit_shielding(&mut db, value);
+        db.mine().unwrap();
+    }
+    // Advance the witness of the first note to the tip, past the block about to be disconnected.
+    let (_height, _hash, anchor) = db.tip_summary().unwrap();
+    assert_eq!(db.rebuild_witness(1).unwrap(), anchor);
+
+    assert_eq!(db.disconnect_tip().unwrap(), 3);
+    let (_height, _hash, anchor) = db.tip_summary().unwrap();
+    assert_eq!(db.diagnose_spend(1).unwrap().witness_root, anchor);
+    let values: Vec<u64> = db
+        .get_wallet_notes()
+        .unwrap()
+        .iter()
+        .map(|note| note.note.value().inner())
+        .collect();
+    assert_eq!(values, vec![1000, 2000]);
+}
@@ -1,13 +1,58 @@
+// Nothing in the binary calls the async facade yet, it's there for async services built on
+// top of `Db`.
+#[cfg(feature = "tokio")]
+#[allow(dead_code)]
+mod async_db;
+mod builder;
 mod cli;
 mod db;
+mod params;
 mod types;
+mod units;
 
 use clap::Parser as _;
-use miette::IntoDiagnostic;
+use miette::{miette, IntoDiagnostic};
+use units::{Amount, SignedAmount};
+
+/// Read a password from the terminal without echoing it.
+fn prompt_password(prompt: &str) -> miette::Result<String> {
+    rpassword::prompt_password(prompt).into_diagnostic()
+}
 
 fn main() -> miette::Result<()> {
     let cli = cli::Cli::parse();
-    let mut db = db::Db::new()?;
+    let db_path = match (&cli.db, &cli.datadir) {
+        _ if cli.in_memory => None,
+        (Some(db_path), _) => Some(db_path.clone()),
+        (None, Some(datadir)) => Some(datadir.join(db::DB_FILE_NAME)),
+        (None, None) => Some(db::Db::default_path()?),
+    };
+    let mut db = match &db_path {
+        None => db::Db::new_in_memory()?,
+        Some(db_path) if db::Db::is_encrypted(db_path)? => {
+            let password = prompt_password("Wallet password: ")?;
+            db::Db::new_encrypted(db_path, &password)?
+        }
+        Some(db_path) if cli.db.is_some() || cli.datadir.is_some() => db::Db::new_at(db_path)?,
+        Some(_) => db::Db::new()?,
+    };
+    if let Some(pages) = cli.wal_autocheckpoint {
+        db.set_wal_autocheckpoint(pages)?;
+    }
+    db.params.verify_proofs = cli.verify_proofs;
+    if let Some(min_fee) = cli.min_fee {
+        db.params.min_fee = min_fee;
+    }
+    let pending = db.pending_transaction_state()?;
+    if !pending.is_empty() && !matches!(cli.command, cli::Commands::ResumePending) {
+        eprintln!(
+            "staged transaction: {} inputs, {} outputs, {} shielded inputs, {} shielded outputs (see resume-pending)",
+            pending.inputs.len(),
+            pending.outputs.len(),
+            pending.shielded_inputs.len(),
+            pending.shielded_outputs.len(),
+        );
+    }
     match &cli.command {
         cli::Commands::Wallet => {
             let mut transparent_value_in = 0;
@@ -18,7 +63,7 @@ fn main() -> miette::Result<()> {
             println!("Inputs: ");
             for utxo_id in inputs {
                 let value = db::Db::get_utxo_value(&tx, utxo_id)?;
-                println!("utxo_id: {utxo_id} value: {value}");
+                println!("utxo_id: {utxo_id} value: {}", Amount(value));
 
                 transparent_value_in += value;
             }
@@ -28,15 +73,15 @@ fn main() -> miette::Result<()> {
 
             println!("Outputs: ");
             for output in outputs {
-                println!("value: {}", output.value);
+                println!("value: {}", Amount(output.value));
 
                 transparent_value_out += output.value;
             }
 
             println!();
 
-            println!("Transparent value in: {transparent_value_in}");
-            println!("Transparent value out: {transparent_value_out}");
+            println!("Transparent value in: {}", Amount(transparent_value_in));
+            println!("Transparent value out: {}", Amount(transparent_value_out));
 
             println!();
 
@@ -46,9 +91,9 @@ fn main() -> miette::Result<()> {
             println!("Shielded inputs: ");
             let shielded_inputs = db::Db::get_shielded_inputs(&tx)?;
             for note_id in &shielded_inputs {
-                let (note, _) = db::Db::get_note(&tx, *note_id)?;
+                let note = db::Db::get_note(&tx, *note_id)?;
                 let value = note.value().inner();
-                println!("note_id: {note_id} value: {value}");
+                println!("note_id: {note_id} value: {}", Amount(value));
 
                 shielded_value_in += value;
             }
@@ -58,17 +103,21 @@ fn main() -> miette::Result<()> {
             println!();
 
             println!("Shielded outputs: ");
-            for (recipient, value) in shielded_outputs {
-                let recipient = bs58::encode(recipient).with_check().into_string();
-                println!("recipient: {recipient}, value: {value}");
+            for output in shielded_outputs {
+                let recipient = db::encode_shielded_address(&db.params, &output.recipient);
+                let value = Amount(output.value);
+                match output.memo.as_ref().and_then(db::memo_text) {
+                    Some(memo) => println!("recipient: {recipient}, value: {value}, memo: {memo}"),
+                    None => println!("recipient: {recipient}, value: {value}"),
+                }
 
-                shielded_value_out += value;
+                shielded_value_out += value.0;
             }
 
             println!();
 
-            println!("Sielded value in: {shielded_value_in}");
-            println!("Sielded value out: {shielded_value_out}");
+            println!("Sielded value in: {}", Amount(shielded_value_in));
+            println!("Sielded value out: {}", Amount(shielded_value_out));
 
             let fee = transparent_value_in as i64 + shielded_value_in as i64
                 - transparent_value_out as i64
@@ -76,7 +125,35 @@ fn main() -> miette::Result<()> {
 
             println!();
 
-            println!("Transaction fee: {fee}");
+            println!("Transaction fee: {}", SignedAmount(fee));
+
+            println!();
+
+            println!("Addresses: ");
+            for account in db::Db::get_accounts(&tx)? {
+                for (index, address) in db::Db::list_addresses(&tx, account)? {
+                    let address =
+                        db::encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+                    println!("account: {account} index: {index} address: {address}");
+                }
+            }
+            drop(tx);
+
+            println!();
+
+            println!("Notes: ");
+            for note in db.get_wallet_notes()? {
+                let id = note.id;
+                let position = u64::from(note.witness.witnessed_position());
+                let value = Amount(note.note.value().inner());
+                match note.memo {
+                    Some(memo) => println!(
+                        "id: {id} position: {position} value: {value} memo: {}",
+                        db::memo_display(&memo)
+                    ),
+                    None => println!("id: {id} position: {position} value: {value}"),
+                }
+            }
         }
         cli::Commands::CreateUtxo { value } => {
             db.create_utxo(*value)?;
@@ -84,20 +161,372 @@ fn main() -> miette::Result<()> {
         cli::Commands::SpendUtxo { utxo_id } => {
             db.spend_utxo(*utxo_id)?;
         }
-        cli::Commands::CreateNote { value, recipient } => {
-            db.create_note(recipient.clone(), *value)?;
+        cli::Commands::CreateNote {
+            value,
+            recipient,
+            change,
+            memo,
+            account,
+        } => {
+            let memo = memo.as_deref().map(db::memo_from_text).transpose()?;
+            if *change {
+                db.create_change_note(*account, *value, memo)?;
+            } else {
+                db.create_note(*account, recipient.clone(), *value, memo)?;
+            }
         }
         cli::Commands::SpendNote { note_id } => {
             db.spend_note(*note_id)?;
         }
-        cli::Commands::SubmitTxn => {
-            db.submit_transaction()?;
+        cli::Commands::SubmitTxn { fee, no_change } => {
+            let pending_utxos = if *no_change {
+                db.submit_transaction()?
+            } else {
+                let fee = fee.unwrap_or(db.params.default_fee());
+                let (change, pending_utxos) = db.submit_transaction_with_change(fee)?;
+                if let Some(change) = change {
+                    println!("change: {}", Amount(change));
+                }
+                pending_utxos
+            };
+            for utxo_id in pending_utxos {
+                println!("pending utxo_id: {utxo_id}");
+            }
         }
         cli::Commands::ClearTxn => {
             db.clear_transaction()?;
         }
-        cli::Commands::Mine => {
-            db.mine()?;
+        cli::Commands::DrySpend {
+            note_id,
+            amount,
+            fee,
+        } => {
+            let fee = fee.unwrap_or(db.params.default_fee());
+            db.dry_spend(*note_id, *amount, fee)?;
+            println!("note {note_id} can be spent");
+        }
+        cli::Commands::EstimateFee => {
+            let fee = db.estimate_pending_fee()?;
+            println!("estimated fee: {}", Amount(fee));
+        }
+        cli::Commands::PreviewChange { fee } => {
+            let change = db.preview_change(*fee)?;
+            if change < 0 {
+                println!("insufficient funds, missing {}", SignedAmount(-change));
+            } else {
+                println!("change: {}", SignedAmount(change));
+            }
+        }
+        cli::Commands::MempoolDeps => {
+            for dependency in db.mempool_dependencies()? {
+                let txid = hex::encode(dependency.txid);
+                if dependency.parents.is_empty() {
+                    println!("txid: {txid} depends on: nothing");
+                } else {
+                    let parents: Vec<String> = dependency.parents.iter().map(hex::encode).collect();
+                    println!("txid: {txid} depends on: {}", parents.join(", "));
+                }
+            }
+        }
+        cli::Commands::Mempool { kind } => {
+            let mut transactions = db.get_pending_transactions()?;
+            if let Some(kind) = kind {
+                transactions.retain(|(_id, transaction)| transaction.kind() == *kind);
+            }
+            if transactions.is_empty() {
+                println!("no pending transactions");
+            }
+            for (id, transaction) in transactions {
+                println!(
+                    "mempool id: {id} kind: {} inputs: {} outputs: {} actions: {} value balance: {}",
+                    transaction.kind(),
+                    transaction.inputs.len(),
+                    transaction.outputs.len(),
+                    transaction.actions.len(),
+                    SignedAmount(transaction.value_balance_orchard),
+                );
+            }
+        }
+        cli::Commands::InspectMempool { kind } => {
+            let mut transactions = db.get_pending_transactions()?;
+            if let Some(kind) = kind {
+                transactions.retain(|(_id, transaction)| transaction.kind() == *kind);
+            }
+            if transactions.is_empty() {
+                println!("no pending transactions");
+            }
+            for (id, transaction) in transactions {
+                let txid = transaction.txid();
+                println!("mempool id: {id} txid: {}", hex::encode(txid));
+                if let Some(label) = db.get_transaction_label(&txid)? {
+                    println!("label: {label}");
+                }
+                println!("kind: {}", transaction.kind());
+                println!("inputs: {:?}", transaction.inputs);
+                let outputs: Vec<String> = transaction
+                    .outputs
+                    .iter()
+                    .map(|output| Amount(output.value).to_string())
+                    .collect();
+                println!("outputs: [{}]", outputs.join(", "));
+                println!("actions: {}", transaction.actions.len());
+                println!(
+                    "value_balance_orchard: {}",
+                    SignedAmount(transaction.value_balance_orchard)
+                );
+                println!();
+            }
+        }
+        cli::Commands::LabelTransaction { txid, label } => {
+            let txid: [u8; 32] = hex::decode(txid)
+                .into_diagnostic()?
+                .try_into()
+                .map_err(|_err| miette!("wrong txid length"))?;
+            db.label_transaction(&txid, label)?;
+        }
+        cli::Commands::ResumePending => {
+            if pending.is_empty() {
+                println!("no staged transaction");
+                return Ok(());
+            }
+            println!("Inputs: ");
+            for utxo_id in &pending.inputs {
+                println!("utxo_id: {utxo_id}");
+            }
+            println!();
+            println!("Outputs: ");
+            for output in &pending.outputs {
+                println!("value: {}", Amount(output.value));
+            }
+            println!();
+            println!("Shielded inputs: ");
+            for note_id in &pending.shielded_inputs {
+                println!("note_id: {note_id}");
+            }
+            println!();
+            println!("Shielded outputs: ");
+            for output in &pending.shielded_outputs {
+                let recipient = db::encode_shielded_address(&db.params, &output.recipient);
+                let value = Amount(output.value);
+                match output.memo.as_ref().and_then(db::memo_text) {
+                    Some(memo) => println!("recipient: {recipient}, value: {value}, memo: {memo}"),
+                    None => println!("recipient: {recipient}, value: {value}"),
+                }
+            }
+            println!();
+            println!("Run submit-txn to submit it or clear-txn to discard it.");
+        }
+        cli::Commands::Mine {
+            timestamp,
+            drop_rejected,
+        } => {
+            let timestamp = match timestamp {
+                Some(timestamp) => *timestamp,
+                None => db::current_timestamp()?,
+            };
+            let result = db.mine_at(timestamp, *drop_rejected)?;
+            if let Some(block_id) = result.block_id {
+                println!("mined block {block_id}");
+            }
+            for txid in &result.mined {
+                println!("mined: {}", hex::encode(txid));
+            }
+            for rejected in &result.rejected {
+                println!(
+                    "rejected: {} (mempool id {}): {}",
+                    hex::encode(rejected.txid),
+                    rejected.mempool_id,
+                    rejected.reason
+                );
+            }
+            if *drop_rejected && !result.rejected.is_empty() {
+                println!("dropped {} rejected transactions", result.rejected.len());
+            }
+        }
+        cli::Commands::DisconnectTip => {
+            let height = db.disconnect_tip()?;
+            println!("disconnected block {height}");
+        }
+        cli::Commands::PeekBlock { timestamp } => {
+            let timestamp = match timestamp {
+                Some(timestamp) => *timestamp,
+                None => db::current_timestamp()?,
+            };
+            match db.peek_next_block(timestamp)? {
+                Some(preview) => {
+                    println!("height: {}", preview.height);
+                    println!("timestamp: {}", preview.timestamp);
+                    println!("hash: {}", hex::encode(preview.hash));
+                    println!("fee: {}", Amount(preview.fee));
+                    for transaction in &preview.block.transactions {
+                        println!(
+                            "txid: {} inputs: {} outputs: {} actions: {}",
+                            hex::encode(transaction.txid()),
+                            transaction.inputs.len(),
+                            transaction.outputs.len(),
+                            transaction.actions.len()
+                        );
+                    }
+                }
+                None => println!("mempool is empty, no block would be mined"),
+            }
+        }
+        cli::Commands::Bench {
+            blocks,
+            txns_per_block,
+        } => {
+            let report = db.bench_mine(*blocks, *txns_per_block)?;
+            println!(
+                "mined {} blocks with {} notes in {:.2?}",
+                report.blocks, report.notes, report.elapsed
+            );
+            println!("blocks/sec: {:.2}", report.blocks_per_sec());
+            println!("notes/sec: {:.2}", report.notes_per_sec());
+        }
+        cli::Commands::MineSelected { txids } => {
+            let txids = txids
+                .iter()
+                .map(|txid| {
+                    hex::decode(txid)
+                        .into_diagnostic()?
+                        .try_into()
+                        .map_err(|_err| miette!("wrong txid length"))
+                })
+                .collect::<miette::Result<Vec<[u8; 32]>>>()?;
+            db.mine_selected(&txids)?;
+        }
+        cli::Commands::MineCoinbase { recipient } => {
+            db.mine_coinbase(recipient.clone())?;
+        }
+        cli::Commands::SetMinerAddress { address } => {
+            db.set_miner_address(address.as_deref())?;
+        }
+        cli::Commands::GetMinerAddress => match db.get_miner_address()? {
+            Some(address) => println!("{address}"),
+            None => println!("coinbase rewards are paid to transparent utxos"),
+        },
+        cli::Commands::Status => {
+            let status = db.status()?;
+            println!("Schema version: {}", status.schema_version);
+            println!("Height: {}", status.height);
+            println!("Mempool transactions: {}", status.mempool_size);
+            println!(
+                "Total transparent value: {}",
+                Amount(status.transparent_value)
+            );
+            println!("Total shielded value: {}", Amount(status.shielded_value));
+            println!("Nullifiers: {}", status.nullifier_count);
+            println!("Note commitment tree size: {}", status.tree_size);
+        }
+        cli::Commands::SupplyAudit => {
+            let audit = db.supply_audit()?;
+            println!("Issued: {}", Amount(audit.issued));
+            println!("Conjured: {}", Amount(audit.conjured));
+            println!();
+            println!("Transparent pool: {}", Amount(audit.transparent));
+            println!("Shielded pool: {}", Amount(audit.shielded));
+            println!("Fees: {}", Amount(audit.fees));
+            println!();
+            println!("Balanced: {}", audit.is_balanced());
+            if audit.fees > 0 {
+                println!();
+                println!(
+                    "warning: {} paid as fees has left the system, fees aren't credited to miners yet",
+                    Amount(audit.fees)
+                );
+            }
+        }
+        cli::Commands::SearchMemos { query } => {
+            for (txid, memo) in db.search_memos(query)? {
+                match db.get_transaction_label(&txid)? {
+                    Some(label) => println!("{} ({label}): {memo}", hex::encode(txid)),
+                    None => println!("{}: {memo}", hex::encode(txid)),
+                }
+            }
+        }
+        cli::Commands::Tip => {
+            let (height, hash, anchor) = db.tip_summary()?;
+            println!("height: {height}");
+            println!("hash: {}", hex::encode(hash));
+            println!("anchor: {}", hex::encode(anchor));
+        }
+        cli::Commands::Chain => {
+            let summary = db.chain_summary()?;
+            println!("height: {}", summary.height);
+            println!("blocks: {}", db.get_block_count()?);
+            println!("total fees: {}", Amount(summary.total_fees));
+            println!("transactions: {}", summary.transactions);
+        }
+        cli::Commands::TreeSize => {
+            println!("{}", db.tree_size()?);
+        }
+        cli::Commands::Block { id } => {
+            let (fee, block) = db
+                .get_block(*id)?
+                .ok_or_else(|| miette!("block {id} does not exist"))?;
+            println!("height: {id}");
+            println!("fee: {}", Amount(fee));
+            println!("transactions: {}", block.transactions.len());
+            for transaction in &block.transactions {
+                println!(
+                    "txid: {} inputs: {} outputs: {} actions: {}",
+                    hex::encode(transaction.txid()),
+                    transaction.inputs.len(),
+                    transaction.outputs.len(),
+                    transaction.actions.len()
+                );
+            }
+        }
+        cli::Commands::FrontierOmmers => {
+            for ommer in db.frontier_ommers()? {
+                println!("{}", hex::encode(ommer));
+            }
+        }
+        cli::Commands::ExportChain { path } => {
+            let count = db.export_chain(path)?;
+            println!("exported {count} blocks");
+        }
+        cli::Commands::ImportChain { path } => {
+            let count = db.import_chain(path)?;
+            println!("imported {count} blocks");
+        }
+        cli::Commands::ExportBlock { height } => {
+            println!("{}", db.export_block(*height)?);
+        }
+        cli::Commands::ImportBlock { hex } => {
+            let (height, hash) = db.import_block(hex)?;
+            println!("height: {height}");
+            println!("hash: {}", hex::encode(hash));
+        }
+        cli::Commands::VerifyChain => {
+            db.verify_chain()?;
+            println!("chain is valid");
+        }
+        cli::Commands::CheckDb => {
+            let checked = db.verify_addresses()?;
+            println!("{checked} addresses match the wallet seed");
+            let issues = db.check_integrity()?;
+            for issue in &issues {
+                println!("issue: {issue}");
+            }
+            if !issues.is_empty() {
+                return Err(miette!("found {} integrity issues", issues.len()));
+            }
+        }
+        cli::Commands::CompareChains { other_path } => {
+            let db_path = db_path
+                .as_ref()
+                .ok_or_else(|| miette!("an in-memory chain can't be compared"))?;
+            let comparison = db::Db::compare_chains(db_path, other_path)?;
+            println!("height: {}", comparison.height);
+            println!("other height: {}", comparison.other_height);
+            if comparison.tips_agree() {
+                println!("tips agree");
+            } else if let Some(height) = comparison.divergence_height {
+                println!("chains diverge at height {height}");
+            } else {
+                println!("one chain is a prefix of the other");
+            }
         }
         cli::Commands::GetMnemonic => {
             let tx = db.conn.transaction().into_diagnostic()?;
@@ -105,37 +534,204 @@ fn main() -> miette::Result<()> {
             let phrase = mnemonic.phrase().to_string();
             println!("{phrase}");
         }
-        cli::Commands::GetNewAddress => {
-            let address = db.get_new_address()?;
+        cli::Commands::RestoreWallet { phrase, force } => {
+            db.restore_from_mnemonic(phrase, *force)?;
+        }
+        cli::Commands::InitWallet { path } => {
+            let mut wallet = db::Db::init_fresh(path)?;
+            let tx = wallet.conn.transaction().into_diagnostic()?;
+            let mnemonic = db::Db::get_mnemonic(&tx)?;
+            println!("{}", mnemonic.phrase());
+        }
+        cli::Commands::NewAccount => {
+            let account = db.new_account()?;
+            println!("{account}");
+        }
+        cli::Commands::GetNewAddress { account } => {
+            let address = db.get_new_address(*account)?;
             let address_bytes = address.to_raw_address_bytes();
-            let address_string = bs58::encode(address_bytes).with_check().into_string();
+            let address_string = db::encode_shielded_address(&db.params, &address_bytes);
             println!("{address_string}");
         }
-        cli::Commands::ValuePools => {
+        cli::Commands::GetAddressAt { index, account } => {
+            let tx = db.conn.transaction().into_diagnostic()?;
+            let address = db::Db::get_address_at(&tx, *account, *index)?.ok_or_else(|| {
+                miette!("no address has been generated at index {index} of account {account}")
+            })?;
+            let address_string =
+                db::encode_shielded_address(&db.params, &address.to_raw_address_bytes());
+            println!("{address_string}");
+        }
+        cli::Commands::GetIvk => {
+            println!("{}", db.incoming_viewing_key(0)?);
+        }
+        cli::Commands::ScanBlock { block_id, ivk } => {
+            for (value, memo) in db.scan_block_with_ivk(*block_id, ivk)? {
+                match memo {
+                    Some(memo) => println!("value: {} memo: {memo}", Amount(value)),
+                    None => println!("value: {}", Amount(value)),
+                }
+            }
+        }
+        cli::Commands::MyOutputs { block_id } => {
+            for (value, memo) in db.my_outputs_in_block(*block_id)? {
+                match memo {
+                    Some(memo) => println!("value: {} memo: {memo}", Amount(value)),
+                    None => println!("value: {}", Amount(value)),
+                }
+            }
+        }
+        cli::Commands::Fingerprint => {
+            let fingerprint = db.wallet_fingerprint()?;
+            println!("{fingerprint}");
+        }
+        cli::Commands::GetChangeAddress => {
+            let address = db.next_change_address(0)?;
+            let address_bytes = address.to_raw_address_bytes();
+            let address_string = db::encode_shielded_address(&db.params, &address_bytes);
+            println!("{address_string}");
+        }
+        cli::Commands::ValuePools { account } => {
             let total_transparent_value = db.get_total_transparent_value()?;
-            println!("Total transparent value: {total_transparent_value}");
+            println!(
+                "Total transparent value: {}",
+                Amount(total_transparent_value)
+            );
 
-            let total_shielded_value = db.get_total_shielded_value()?;
-            println!("Total shielded value: {total_shielded_value}");
+            let total_shielded_value = db.get_total_shielded_value(*account)?;
+            println!("Total shielded value: {}", Amount(total_shielded_value));
+
+            let spendable_shielded_value = db.get_spendable_shielded_value(*account)?;
+            println!(
+                "Spendable shielded value: {}",
+                Amount(spendable_shielded_value)
+            );
+        }
+        cli::Commands::BalanceAt { block_id } => {
+            let (transparent, shielded) = db.balance_at(*block_id)?;
+            println!("Transparent value: {}", Amount(transparent));
+            println!("Shielded value: {}", Amount(shielded));
         }
         cli::Commands::ConjureUtxo { value } => {
             db.conjure_utxo(*value)?;
         }
+        cli::Commands::SpendValue { value } => {
+            for (utxo_id, value) in db.spend_value(*value)? {
+                println!("utxo_id: {utxo_id} value: {}", Amount(value));
+            }
+        }
+        cli::Commands::LockUtxo { utxo_id } => db.lock_utxo(*utxo_id)?,
+        cli::Commands::UnlockUtxo { utxo_id } => db.unlock_utxo(*utxo_id)?,
         cli::Commands::GetUtxos => {
             println!("transparent utxos: ");
             let utxos = db.get_utxos()?;
-            for (id, value) in utxos {
-                println!("id: {id} value: {value}");
+            for (id, value, locked) in utxos {
+                if locked {
+                    println!("id: {id} value: {} (locked)", Amount(value));
+                } else {
+                    println!("id: {id} value: {}", Amount(value));
+                }
             }
             println!();
             println!("shielded notes: ");
             let notes = db.get_wallet_notes()?;
-            for (id, note, _witness) in notes {
-                let recipient = note.recipient().to_raw_address_bytes();
-                let recipient = bs58::encode(&recipient).with_check().into_string();
-                let value = note.value().inner();
-                println!("id: {id} recipient: {recipient} value: {value}");
+            for note in notes {
+                let id = note.id;
+                let recipient = note.note.recipient().to_raw_address_bytes();
+                let recipient = db::encode_shielded_address(&db.params, &recipient);
+                let value = note.note.value().inner();
+                println!("id: {id} recipient: {recipient} value: {}", Amount(value));
+            }
+        }
+        cli::Commands::ListSpentNotes => {
+            for (id, value, spent_block) in db.list_spent_notes()? {
+                println!(
+                    "id: {id} value: {} spent in block: {spent_block}",
+                    Amount(value)
+                );
+            }
+        }
+        cli::Commands::UtxoInfo { id } => {
+            let info = db.utxo_info(*id)?;
+            println!("id: {}", info.id);
+            println!("value: {}", Amount(info.value));
+            match (info.created_block, info.mempool_tx) {
+                (Some(block_id), _) => println!("created in block: {block_id}"),
+                (None, Some(mempool_tx)) => {
+                    println!("created in block: pending (mempool transaction {mempool_tx})")
+                }
+                (None, None) => println!("created in block: none (conjured)"),
+            }
+            match info.spent_block {
+                Some(block_id) => println!("spent in block: {block_id}"),
+                None => println!("spent in block: unspent"),
+            }
+            if info.pending_spend {
+                println!("spent by pending transaction");
+            }
+        }
+        cli::Commands::PruneNotes { depth } => {
+            let pruned = db.prune_spent_notes(*depth)?;
+            println!("pruned {pruned} spent notes");
+        }
+        cli::Commands::DumpNullifiers => {
+            let nullifiers = db.all_nullifiers()?;
+            for (nullifier, block_id) in nullifiers {
+                match block_id {
+                    Some(block_id) => println!("{} block: {block_id}", hex::encode(nullifier)),
+                    None => println!("{} block: unknown", hex::encode(nullifier)),
+                }
+            }
+        }
+        cli::Commands::NoteNullifier { note_id } => {
+            let nullifier = db.note_nullifier(*note_id)?;
+            println!("{}", hex::encode(nullifier));
+            println!("spent: {}", db.is_nullifier_spent(&nullifier)?);
+        }
+        cli::Commands::DiagnoseSpend { note_id } => {
+            let diagnosis = db.diagnose_spend(*note_id)?;
+            println!("note: {}", diagnosis.note_id);
+            println!("position: {}", diagnosis.position);
+            match diagnosis.created_block {
+                Some(block_id) => println!("created in block: {block_id}"),
+                None => println!("created in block: unknown (imported)"),
+            }
+            match diagnosis.anchor_height {
+                Some(height) => println!("anchor height: {height}"),
+                None => println!("anchor height: none (empty tree)"),
+            }
+            println!("anchor: {}", hex::encode(diagnosis.anchor));
+            println!("witness root: {}", hex::encode(diagnosis.witness_root));
+            match diagnosis.path_root {
+                Some(root) => println!("path root: {}", hex::encode(root)),
+                None => println!("path root: none, the note is not in the anchor tree"),
+            }
+            println!("mature: {}", diagnosis.is_mature());
+            println!("match: {}", diagnosis.path_matches_anchor());
+        }
+        cli::Commands::FindMissing => {
+            for (block_id, value) in db.find_missing_notes()? {
+                println!("block: {block_id} value: {}", Amount(value));
+            }
+        }
+        cli::Commands::RebuildWitness { note_id } => {
+            let root = db.rebuild_witness(*note_id)?;
+            println!("witness root: {}", hex::encode(root));
+        }
+        cli::Commands::ExportNote { note_id } => {
+            println!("{}", db.export_spendable_note(*note_id)?);
+        }
+        cli::Commands::ImportNote => {
+            let json = std::io::read_to_string(std::io::stdin()).into_diagnostic()?;
+            db.import_spendable_note(&json)?;
+        }
+        cli::Commands::EncryptWallet => {
+            let password = prompt_password("New wallet password: ")?;
+            let confirmation = prompt_password("Repeat wallet password: ")?;
+            if password != confirmation {
+                return Err(miette!("passwords do not match"));
             }
+            db.encrypt_wallet(&password)?;
         }
     }
     Ok(())
@@ -0,0 +1,70 @@
+/// Consensus parameters of the sandbox network.
+#[derive(Clone, Debug)]
+pub struct NetworkParams {
+    /// Number of blocks that must be mined on top of the block that created a transparent utxo
+    /// before it can be spent. 0 disables the check.
+    pub transparent_maturity: u32,
+    /// Coinbase reward of the first block.
+    pub initial_block_reward: u64,
+    /// Number of blocks after which the coinbase reward halves. 0 disables halving.
+    pub halving_interval: u32,
+    /// Version byte prepended to encoded shielded addresses, identifying the network they
+    /// belong to.
+    pub address_version: u8,
+    /// Require valid Orchard proofs in mined blocks. Signatures are checked either way. Defaults
+    /// to false to keep the sandbox fast, but only true is consensus-safe: without it, invalid
+    /// proofs are accepted.
+    pub verify_proofs: bool,
+    /// Allow creating utxos out of thin air with `conjure_utxo`. Only sandbox networks should
+    /// enable this.
+    pub allow_conjure: bool,
+    /// Bundles are anchored to the note commitment tree as of this many blocks below the tip,
+    /// so notes are only spendable once they are this deep. Defaults to 3.
+    pub anchor_depth: u32,
+    /// Wallet default, not a consensus rule: the fee paid when transparent change is generated
+    /// automatically and no fee is given, and the smallest fee accepted then. `--min-fee`
+    /// doesn't change it, see `default_fee`.
+    pub default_change_fee: u64,
+    /// Consensus rule: transactions paying a lower fee are rejected from new blocks. Set by
+    /// `--min-fee`. Defaults to 0, accepting any fee.
+    pub min_fee: u64,
+    /// Fee per logical action of the fee estimate, see `Db::estimate_fee`.
+    pub marginal_fee: u64,
+    /// Smallest number of logical actions charged by the fee estimate, see `Db::estimate_fee`.
+    pub grace_actions: usize,
+}
+
+impl NetworkParams {
+    /// Coinbase reward of the block at `height`.
+    pub fn block_reward(&self, height: u32) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_block_reward;
+        }
+        let halvings = (height - 1) / self.halving_interval;
+        self.initial_block_reward.checked_shr(halvings).unwrap_or(0)
+    }
+
+    /// Fee paid when none is given: the default change fee, raised to the minimum fee so that
+    /// the transaction can be mined.
+    pub fn default_fee(&self) -> u64 {
+        self.default_change_fee.max(self.min_fee)
+    }
+}
+
+impl Default for NetworkParams {
+    fn default() -> Self {
+        Self {
+            transparent_maturity: 0,
+            initial_block_reward: 5_000_000_000,
+            halving_interval: 210_000,
+            address_version: 0x1c,
+            verify_proofs: false,
+            allow_conjure: true,
+            anchor_depth: 3,
+            default_change_fee: 100,
+            min_fee: 0,
+            marginal_fee: 5_000,
+            grace_actions: 2,
+        }
+    }
+}
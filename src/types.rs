@@ -1,11 +1,16 @@
+use incrementalmerkletree::witness::IncrementalWitness;
+use miette::miette;
 use orchard::{
-    builder::BundleMetadata,
-    bundle::{Authorization, Flags},
+    bundle::{Authorized, Flags},
     note::{ExtractedNoteCommitment, Nullifier, TransmittedNoteCiphertext},
-    Anchor,
+    primitives::redpallas,
+    tree::MerkleHashOrchard,
+    Anchor, Note,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::db::NOTE_COMMITMENT_TREE_DEPTH;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub transactions: Vec<Transaction>,
@@ -39,29 +44,308 @@ pub struct Output {
     pub value: u64,
 }
 
+/// A staged shielded output.
+#[derive(Clone, Debug)]
+pub struct ShieldedOutput {
+    /// Raw recipient address bytes.
+    pub recipient: Vec<u8>,
+    pub value: u64,
+    pub memo: Option<[u8; 512]>,
+}
+
+/// Where a transparent utxo came from and where it went.
+#[derive(Clone, Debug)]
+pub struct UtxoInfo {
+    pub id: u32,
+    pub value: u64,
+    /// `None` for utxos conjured outside of any block and for pending utxos.
+    pub created_block: Option<u32>,
+    pub spent_block: Option<u32>,
+    /// The mempool transaction that creates this utxo, if it isn't mined yet.
+    pub mempool_tx: Option<u32>,
+    /// The utxo is an input of the pending transaction.
+    pub pending_spend: bool,
+}
+
+/// How a wallet note relates to the anchor spends are built against, see `Db::diagnose_spend`.
+#[derive(Clone, Debug)]
+pub struct SpendDiagnosis {
+    pub note_id: u32,
+    /// Position of the note commitment in the note commitment tree.
+    pub position: u64,
+    /// `None` for imported notes.
+    pub created_block: Option<u32>,
+    /// `None` while the chain is too short to have an anchor block, see `Db::get_bundle_anchor`.
+    pub anchor_height: Option<u32>,
+    pub anchor: [u8; 32],
+    /// Root of the stored witness, which is taken when the note is mined.
+    pub witness_root: [u8; 32],
+    /// Root of the merkle path spends use, rebuilt from the chain at the anchor block. `None`
+    /// if the note isn't in the chain up to the anchor block.
+    pub path_root: Option<[u8; 32]>,
+}
+
+impl SpendDiagnosis {
+    /// The note is in the tree the anchor commits to, so it can be spent.
+    pub fn is_mature(&self) -> bool {
+        self.path_root.is_some()
+    }
+
+    pub fn path_matches_anchor(&self) -> bool {
+        self.path_root == Some(self.anchor)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChainComparison {
+    pub height: u32,
+    pub other_height: u32,
+    /// First height at which the chains have different blocks, `None` if one chain is a prefix
+    /// of the other.
+    pub divergence_height: Option<u32>,
+}
+
+impl ChainComparison {
+    pub fn tips_agree(&self) -> bool {
+        self.height == self.other_height && self.divergence_height.is_none()
+    }
+}
+
+/// Where all value in the system came from and where it is now.
+#[derive(Clone, Debug)]
+pub struct SupplyAudit {
+    /// Value created by coinbase transactions.
+    pub issued: u64,
+    /// Value created by conjured utxos outside of any block.
+    pub conjured: u64,
+    /// Fees paid by mined transactions. No output collects them yet, so this value has left
+    /// the transparent and Orchard pools for good.
+    pub fees: u64,
+    pub transparent: u64,
+    pub shielded: u64,
+}
+
+impl SupplyAudit {
+    pub fn is_balanced(&self) -> bool {
+        self.issued + self.conjured == self.transparent + self.shielded + self.fees
+    }
+}
+
+/// Totals over every block of the chain, see `Db::chain_summary`.
+#[derive(Clone, Debug)]
+pub struct ChainSummary {
+    pub height: u32,
+    pub total_fees: u64,
+    pub transactions: u64,
+}
+
+/// Overview of the node and wallet state.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub schema_version: u32,
+    pub height: u32,
+    pub mempool_size: u32,
+    pub transparent_value: u64,
+    pub shielded_value: u64,
+    pub nullifier_count: u32,
+    /// Number of note commitments in the note commitment tree.
+    pub tree_size: u64,
+}
+
+/// The block `Db::mine_at` would produce, see `Db::peek_next_block`.
+#[derive(Clone, Debug)]
+pub struct BlockPreview {
+    pub height: u32,
+    pub timestamp: i64,
+    pub hash: [u8; 32],
+    pub fee: u64,
+    pub block: Block,
+}
+
+/// Outcome of `Db::mine_at`.
+#[derive(Clone, Debug)]
+pub struct MineResult {
+    /// `None` if no block was mined because no mempool transaction was valid.
+    pub block_id: Option<u32>,
+    /// Txids of the transactions in the mined block.
+    pub mined: Vec<[u8; 32]>,
+    pub rejected: Vec<RejectedTransaction>,
+}
+
+/// Mempool transactions split into the ones valid on top of the chain, with their mempool ids,
+/// and the invalid ones.
+#[derive(Clone, Debug)]
+pub struct TransactionSelection {
+    pub accepted: Vec<(u32, Transaction)>,
+    pub rejected: Vec<RejectedTransaction>,
+}
+
+/// A mempool transaction left out of a mined block because it is invalid.
+#[derive(Clone, Debug)]
+pub struct RejectedTransaction {
+    pub mempool_id: u32,
+    pub txid: [u8; 32],
+    pub reason: String,
+}
+
+/// A mempool transaction and the mempool transactions whose pending utxos it spends, see
+/// `Db::mempool_dependencies`.
+#[derive(Clone, Debug)]
+pub struct MempoolDependency {
+    pub txid: [u8; 32],
+    pub parents: Vec<[u8; 32]>,
+}
+
+/// Result of `Db::bench_mine`.
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub blocks: u32,
+    pub notes: u32,
+    pub elapsed: std::time::Duration,
+}
+
+impl BenchReport {
+    pub fn blocks_per_sec(&self) -> f64 {
+        self.blocks as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn notes_per_sec(&self) -> f64 {
+        self.notes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// A wallet note with its witness, hex encoded, for moving notes between wallets sharing a
+/// seed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpendableNote {
+    pub recipient: String,
+    pub value: u64,
+    pub rho: String,
+    pub rseed: String,
+    pub witness: String,
+}
+
+/// A note of the wallet, see `Db::get_wallet_notes`.
+#[derive(Clone, Debug)]
+pub struct WalletNote {
+    pub id: u32,
+    pub note: Note,
+    pub witness: IncrementalWitness<MerkleHashOrchard, NOTE_COMMITMENT_TREE_DEPTH>,
+    /// `None` if the note has no memo: all zeros or the ZIP 302 empty memo.
+    pub memo: Option<[u8; 512]>,
+}
+
+/// Components staged for the transaction that hasn't been submitted yet.
+#[derive(Clone, Debug)]
+pub struct PendingTransactionState {
+    pub inputs: Vec<u32>,
+    pub outputs: Vec<Output>,
+    pub shielded_inputs: Vec<u32>,
+    pub shielded_outputs: Vec<ShieldedOutput>,
+}
+
+impl PendingTransactionState {
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+            && self.outputs.is_empty()
+            && self.shielded_inputs.is_empty()
+            && self.shielded_outputs.is_empty()
+    }
+}
+
+/// Transactions with Orchard actions are only valid with the proof and signatures authorizing
+/// them, see `Db::verify_authorization`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
     pub inputs: Vec<u32>,
     pub outputs: Vec<Output>,
     pub actions: Vec<Action>,
     pub value_balance_orchard: i64,
+    pub authorization: Option<BundleAuthorization>,
+}
+
+/// How a transaction moves value between the transparent and Orchard pools, see
+/// `Transaction::kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionKind {
+    /// No Orchard actions.
+    Transparent,
+    /// Moves value into the Orchard pool.
+    Shield,
+    /// Moves value out of the Orchard pool.
+    Deshield,
+    /// Only moves value within the Orchard pool.
+    Shielded,
+}
+
+impl std::fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Transparent => "transparent",
+            Self::Shield => "shield",
+            Self::Deshield => "deshield",
+            Self::Shielded => "shielded",
+        };
+        f.write_str(kind)
+    }
+}
+
+impl std::str::FromStr for TransactionKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "transparent" => Ok(Self::Transparent),
+            "shield" => Ok(Self::Shield),
+            "deshield" => Ok(Self::Deshield),
+            "shielded" => Ok(Self::Shielded),
+            _ => Err(format!(
+                "unknown transaction kind {kind:?}, expected transparent, shield, deshield or shielded"
+            )),
+        }
+    }
+}
+
+/// Proof and signatures authorizing the Orchard actions of a transaction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BundleAuthorization {
+    /// Note commitment tree root the proof was created against.
+    pub anchor: [u8; 32],
+    pub proof: Vec<u8>,
+    /// One 64 byte spend authorization signature per action, in action order.
+    pub spend_auth_signatures: Vec<Vec<u8>>,
+    /// 64 byte binding signature.
+    pub binding_signature: Vec<u8>,
 }
 
 impl Transaction {
-    pub fn to_bundle(
+    /// Classify the transaction by its Orchard value balance: a negative balance takes value from
+    /// the transparent pool into the Orchard pool, a positive one the reverse. Transactions with
+    /// only Orchard actions are shielded even though their fee leaves the Orchard pool.
+    pub fn kind(&self) -> TransactionKind {
+        if self.actions.is_empty() {
+            return TransactionKind::Transparent;
+        }
+        if self.inputs.is_empty() && self.outputs.is_empty() {
+            return TransactionKind::Shielded;
+        }
+        match self.value_balance_orchard.signum() {
+            -1 => TransactionKind::Shield,
+            1 => TransactionKind::Deshield,
+            _ => TransactionKind::Shielded,
+        }
+    }
+
+    /// The actions as a sandbox-only bundle without their authorization, which can't be
+    /// verified. Only for reading the actions, like trial decrypting their outputs; use
+    /// `to_authorized_bundle` to check them.
+    pub fn to_unauthorized_bundle(
         &self,
         anchor: Anchor,
     ) -> Option<orchard::bundle::Bundle<orchard::bundle::testing::Unauthorized, i64>> {
-        let actions: Vec<orchard::Action<()>> = self
-            .actions
-            .iter()
-            .cloned()
-            .map(|action| action.into())
-            .collect();
-        let actions = match nonempty::NonEmpty::from_vec(actions) {
-            Some(actions) => actions,
-            None => return None,
-        };
+        let actions: Vec<orchard::Action<()>> =
+            self.actions.iter().map(to_orchard_action).collect();
+        let actions = nonempty::NonEmpty::from_vec(actions)?;
         let flags = Flags::ENABLED;
         let value_balance_orchard = self.value_balance_orchard;
         let authorization = orchard::bundle::testing::Unauthorized;
@@ -74,39 +358,96 @@ impl Transaction {
         ))
     }
 
-    pub fn from_bundle<T: Authorization>(
+    /// The authorized bundle, `None` if the transaction has no actions, no authorization or a
+    /// malformed one.
+    pub fn to_authorized_bundle(&self) -> Option<orchard::Bundle<Authorized, i64>> {
+        let authorization = self.authorization.as_ref()?;
+        if authorization.spend_auth_signatures.len() != self.actions.len() {
+            return None;
+        }
+        let actions = self
+            .actions
+            .iter()
+            .zip(&authorization.spend_auth_signatures)
+            .map(|(action, signature)| {
+                let signature: [u8; 64] = signature.clone().try_into().ok()?;
+                let signature = redpallas::Signature::<redpallas::SpendAuth>::from(signature);
+                let action = orchard::Action::try_from(action).ok()?;
+                Some(action.map(|()| signature))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let actions = nonempty::NonEmpty::from_vec(actions)?;
+        let anchor = Option::from(Anchor::from_bytes(authorization.anchor))?;
+        let binding_signature: [u8; 64] =
+            authorization.binding_signature.clone().try_into().ok()?;
+        let authorization = Authorized::from_parts(
+            orchard::Proof::new(authorization.proof.clone()),
+            redpallas::Signature::from(binding_signature),
+        );
+        Some(orchard::Bundle::from_parts(
+            actions,
+            Flags::ENABLED,
+            self.value_balance_orchard,
+            anchor,
+            authorization,
+        ))
+    }
+
+    pub fn from_authorized_bundle(
         inputs: Vec<u32>,
         outputs: Vec<Output>,
-        bundle: &Option<(orchard::bundle::Bundle<T, i64>, BundleMetadata)>,
+        bundle: &orchard::Bundle<Authorized, i64>,
     ) -> Self {
-        match bundle {
-            Some((bundle, _bundle_metadata)) => {
-                let mut actions = vec![];
-                for action in bundle.actions() {
-                    let action = Action::from(action);
-                    actions.push(action);
-                }
-                Self {
-                    inputs,
-                    outputs,
-                    actions,
-                    value_balance_orchard: *bundle.value_balance(),
-                }
-            }
-            None => Self {
-                inputs,
-                outputs,
-                actions: vec![],
-                value_balance_orchard: 0,
-            },
+        let actions = bundle.actions().iter().map(Action::from).collect();
+        let spend_auth_signatures = bundle
+            .actions()
+            .iter()
+            .map(|action| <[u8; 64]>::from(action.authorization()).to_vec())
+            .collect();
+        let authorization = BundleAuthorization {
+            anchor: bundle.anchor().to_bytes(),
+            proof: bundle.authorization().proof().as_ref().to_vec(),
+            spend_auth_signatures,
+            binding_signature: <[u8; 64]>::from(bundle.authorization().binding_signature())
+                .to_vec(),
+        };
+        Self {
+            inputs,
+            outputs,
+            actions,
+            value_balance_orchard: *bundle.value_balance(),
+            authorization: Some(authorization),
         }
     }
 
+    /// Hash of the serialized transaction without its authorization, so proving and signing
+    /// don't change the txid and it can be signed as the sighash.
+    pub fn txid(&self) -> [u8; 32] {
+        let effects = (
+            &self.inputs,
+            &self.outputs,
+            &self.actions,
+            self.value_balance_orchard,
+        );
+        let bytes = bincode::serialize(&effects).expect("transaction serialization can't fail");
+        *blake3::hash(&bytes).as_bytes()
+    }
+
+    /// Check that every action decodes to a valid Orchard action. Transactions from outside,
+    /// like imported blocks, must be checked before anything else reads their actions.
+    pub fn check_actions(&self) -> miette::Result<()> {
+        for (index, action) in self.actions.iter().enumerate() {
+            orchard::Action::try_from(action)
+                .map_err(|err| miette!("action {index} is malformed: {err}"))?;
+        }
+        Ok(())
+    }
+
     /// These must be added to the nullifier set when a block is connected.
     pub fn nullifiers(&self) -> Vec<Nullifier> {
         let mut nullifiers = vec![];
         for action in &self.actions {
-            let action = orchard::Action::from(action);
+            let action = to_orchard_action(action);
             let nullifier = action.nullifier();
             nullifiers.push(*nullifier);
         }
@@ -118,7 +459,7 @@ impl Transaction {
     pub fn extracted_note_commitments(&self) -> Vec<ExtractedNoteCommitment> {
         let mut extracted_note_commitments = vec![];
         for action in &self.actions {
-            let action = orchard::Action::from(action);
+            let action = to_orchard_action(action);
             let extracted_note_commitment = action.cmx();
             extracted_note_commitments.push(*extracted_note_commitment);
         }
@@ -160,32 +501,46 @@ impl<T> From<&orchard::Action<T>> for Action {
     }
 }
 
-impl From<Action> for orchard::Action<()> {
-    fn from(value: Action) -> Self {
-        let nf = orchard::note::Nullifier::from_bytes(&value.nf).unwrap();
-        let rk = orchard::primitives::redpallas::VerificationKey::try_from(value.rk).unwrap();
-        let cmx = orchard::note::ExtractedNoteCommitment::from_bytes(&value.cmx).unwrap();
+impl TryFrom<&Action> for orchard::Action<()> {
+    type Error = miette::Report;
+
+    fn try_from(value: &Action) -> Result<Self, Self::Error> {
+        let nf = Option::from(orchard::note::Nullifier::from_bytes(&value.nf))
+            .ok_or_else(|| miette!("invalid nullifier"))?;
+        let rk = orchard::primitives::redpallas::VerificationKey::try_from(value.rk)
+            .map_err(|_err| miette!("invalid randomized verification key"))?;
+        let cmx = Option::from(orchard::note::ExtractedNoteCommitment::from_bytes(
+            &value.cmx,
+        ))
+        .ok_or_else(|| miette!("invalid note commitment"))?;
         let encrypted_note = orchard::note::TransmittedNoteCiphertext {
             epk_bytes: value.epk_bytes,
-            enc_ciphertext: value.enc_ciphertext.try_into().unwrap(),
-            out_ciphertext: value.out_ciphertext.try_into().unwrap(),
+            enc_ciphertext: value
+                .enc_ciphertext
+                .clone()
+                .try_into()
+                .map_err(|_err| miette!("note ciphertext must be 580 bytes"))?,
+            out_ciphertext: value
+                .out_ciphertext
+                .clone()
+                .try_into()
+                .map_err(|_err| miette!("outgoing ciphertext must be 80 bytes"))?,
         };
-        let cv_net = orchard::value::ValueCommitment::from_bytes(&value.cv_net).unwrap();
-        orchard::Action::from_parts(nf, rk, cmx, encrypted_note, cv_net, ())
+        let cv_net = Option::from(orchard::value::ValueCommitment::from_bytes(&value.cv_net))
+            .ok_or_else(|| miette!("invalid value commitment"))?;
+        Ok(orchard::Action::from_parts(
+            nf,
+            rk,
+            cmx,
+            encrypted_note,
+            cv_net,
+            (),
+        ))
     }
 }
 
-impl From<&Action> for orchard::Action<()> {
-    fn from(value: &Action) -> Self {
-        let nf = orchard::note::Nullifier::from_bytes(&value.nf).unwrap();
-        let rk = orchard::primitives::redpallas::VerificationKey::try_from(value.rk).unwrap();
-        let cmx = orchard::note::ExtractedNoteCommitment::from_bytes(&value.cmx).unwrap();
-        let encrypted_note = orchard::note::TransmittedNoteCiphertext {
-            epk_bytes: value.epk_bytes,
-            enc_ciphertext: value.enc_ciphertext.clone().try_into().unwrap(),
-            out_ciphertext: value.out_ciphertext.clone().try_into().unwrap(),
-        };
-        let cv_net = orchard::value::ValueCommitment::from_bytes(&value.cv_net).unwrap();
-        orchard::Action::from_parts(nf, rk, cmx, encrypted_note, cv_net, ())
-    }
+/// Actions of transactions in the chain or the mempool were checked by `check_actions` before
+/// they got there.
+fn to_orchard_action(action: &Action) -> orchard::Action<()> {
+    orchard::Action::try_from(action).expect("actions are checked before they are used")
 }
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// Raw units per coin. Transparent utxo values and Orchard note values are both counted in raw
+/// units, so they can be added and compared directly.
+pub const COIN: u64 = 100_000_000;
+
+/// Number of decimal places of a coin amount.
+const DECIMALS: usize = 8;
+
+/// A value in raw units, displayed in coins with all decimal places, e.g. `1.00000000`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Amount(pub u64);
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:08}", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+/// A signed value in raw units, like a fee or an Orchard value balance, displayed in coins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SignedAmount(pub i64);
+
+impl fmt::Display for SignedAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{sign}{}", Amount(self.0.unsigned_abs()))
+    }
+}
+
+/// Raw units of a whole number of coins, `None` on overflow.
+pub fn from_coins(coins: u64) -> Option<u64> {
+    coins.checked_mul(COIN)
+}
+
+/// Parse a coin amount entered by the user, like `1`, `0.5` or `21.00000001`, into raw units.
+pub fn parse_amount(amount: &str) -> Result<u64, String> {
+    let (coins, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if coins.is_empty() && fraction.is_empty() || !is_digits(coins) || !is_digits(fraction) {
+        return Err(format!("invalid amount {amount:?}"));
+    }
+    if fraction.len() > DECIMALS {
+        return Err(format!(
+            "amount {amount:?} has more than {DECIMALS} decimal places"
+        ));
+    }
+    let too_large = || format!("amount {amount:?} is too large");
+    let coins = match coins {
+        "" => 0,
+        coins => coins.parse::<u64>().map_err(|_err| too_large())?,
+    };
+    let fraction = match fraction {
+        "" => 0,
+        fraction => {
+            fraction.parse::<u64>().expect("at most 8 digits")
+                * 10u64.pow((DECIMALS - fraction.len()) as u32)
+        }
+    };
+    from_coins(coins)
+        .and_then(|coins| coins.checked_add(fraction))
+        .ok_or_else(too_large)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,31 @@
+use super::*;
+
+#[test]
+fn amounts_are_displayed_in_coins() {
+    assert_eq!(Amount(0).to_string(), "0.00000000");
+    assert_eq!(Amount(COIN).to_string(), "1.00000000");
+    assert_eq!(Amount(21 * COIN + 1).to_string(), "21.00000001");
+    assert_eq!(SignedAmount(-(COIN as i64) / 2).to_string(), "-0.50000000");
+    assert_eq!(SignedAmount(1).to_string(), "0.00000001");
+}
+
+#[test]
+fn decimal_amounts_are_parsed_into_raw_units() {
+    assert_eq!(parse_amount("1"), Ok(COIN));
+    assert_eq!(parse_amount("0.5"), Ok(COIN / 2));
+    assert_eq!(parse_amount(".5"), Ok(COIN / 2));
+    assert_eq!(parse_amount("2."), Ok(2 * COIN));
+    assert_eq!(parse_amount("21.00000001"), Ok(21 * COIN + 1));
+    let max = Amount(u64::MAX).to_string();
+    assert_eq!(parse_amount(&max), Ok(u64::MAX));
+}
+
+#[test]
+fn malformed_amounts_are_rejected() {
+    for amount in ["", ".", "-1", "1.2.3", "1e8", " 1", "0x10"] {
+        assert!(parse_amount(amount).is_err(), "{amount:?} was accepted");
+    }
+    assert!(parse_amount("0.000000001").is_err());
+    assert!(parse_amount("184467440738").is_err());
+    assert!(parse_amount("184467440737.09551616").is_err());
+}